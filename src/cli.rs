@@ -1,13 +1,25 @@
 /// Description of the CLI interface to floki
+use crate::config::{ConfigFormat, Owner};
+use crate::vars::TemplateVar;
 use std::path;
+use std::time::Duration;
 use structopt::StructOpt;
 
 /// Subcommands of the main floki command
 #[derive(Debug, StructOpt)]
 pub(crate) enum Subcommand {
     /// Run a command within the container
-    #[structopt(name = "run")]
-    Run { command: Vec<String> },
+    #[structopt(name = "run", setting = structopt::clap::AppSettings::TrailingVarArg)]
+    Run {
+        /// Run the command directly, rather than through the inner shell
+        #[structopt(long = "no-shell")]
+        no_shell: bool,
+
+        /// The command and its arguments, which are passed to the
+        /// container exactly as given
+        #[structopt(allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
 
     /// Pull the image in the configuration file
     #[structopt(name = "pull")]
@@ -22,9 +34,120 @@ pub(crate) enum Subcommand {
     },
 
     /// Render the configuration file to stdout, performing any templating
-    /// operations.
+    /// operations and merging any override files.
     #[structopt(name = "render")]
-    Render {},
+    Render {
+        /// Convert the rendered configuration to this format: yaml, toml
+        /// or json
+        #[structopt(long = "output", short = "o")]
+        output: Option<ConfigFormat>,
+
+        /// Show every value in the configuration, annotated with the
+        /// file it came from
+        #[structopt(long = "explain", conflicts_with = "output")]
+        explain: bool,
+    },
+
+    /// Print a JSON Schema for the configuration file, for use by editors
+    #[structopt(name = "schema")]
+    Schema {},
+
+    /// Check the configuration file, reporting every problem found
+    #[structopt(name = "validate")]
+    Validate {},
+
+    /// Trust the configuration file to run commands on the host and
+    /// privileged containers, until it changes
+    #[structopt(name = "trust")]
+    Trust {},
+
+    /// Stop trusting the configuration file
+    #[structopt(name = "untrust")]
+    Untrust {},
+
+    /// Show how floki has interpreted the configuration and environment
+    #[structopt(name = "info")]
+    Info {},
+
+    /// Manage floki volumes
+    #[structopt(name = "volume")]
+    Volume {
+        #[structopt(subcommand)]
+        command: VolumeCommand,
+    },
+}
+
+/// Subcommands for managing floki volumes
+#[derive(Debug, StructOpt)]
+pub(crate) enum VolumeCommand {
+    /// List the volumes floki has used, with their size and when they
+    /// were last used
+    #[structopt(name = "list", alias = "ls")]
+    List {},
+
+    /// Show where a volume from the configuration file is stored
+    #[structopt(name = "inspect")]
+    Inspect {
+        /// The name of the volume in the configuration file
+        name: String,
+    },
+
+    /// Clear a volume from the configuration file, deleting its content
+    #[structopt(name = "clear", alias = "rm")]
+    Clear {
+        /// The name of the volume in the configuration file
+        name: String,
+    },
+
+    /// Repair the ownership of volumes from the configuration file
+    #[structopt(name = "chown")]
+    Chown {
+        /// The name of the volume to repair (defaults to all volumes)
+        name: Option<String>,
+
+        /// The new owner: 'user', 'UID' or 'UID:GID' (defaults to the
+        /// volume's configured owner, or the user running floki)
+        #[structopt(long = "owner")]
+        owner: Option<Owner>,
+    },
+
+    /// Remove all volumes which haven't been used recently
+    #[structopt(name = "prune")]
+    Prune {
+        /// Remove volumes not used within this time, e.g. 30d, 12h or 2w
+        #[structopt(long = "older-than", default_value = "30d", parse(try_from_str = parse_duration))]
+        older_than: Duration,
+
+        /// Instead remove all docker volumes created by floki which aren't
+        /// used by any container, regardless of when they were last used
+        #[structopt(long = "dangling")]
+        dangling: bool,
+    },
+}
+
+/// Parse a duration given as a number followed by a unit (s, m, h, d or w)
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let split = s.len() - s.chars().last().map(char::len_utf8).unwrap_or(0);
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration '{s}' - expected e.g. 30d"))?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => {
+            return Err(format!(
+                "invalid unit in duration '{s}' - use s, m, h, d or w"
+            ))
+        }
+    };
+    number
+        .checked_mul(seconds)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("duration '{s}' is too long"))
 }
 
 /// Main CLI interface
@@ -33,9 +156,51 @@ pub(crate) enum Subcommand {
 pub(crate) struct Cli {
     /// Use the specified config instead of searching the tree for a
     /// "floki.yaml" file.
-    #[structopt(long = "config", short = "c")]
+    #[structopt(long = "config", short = "c", env = "FLOKI_CONFIG")]
     pub(crate) config_file: Option<path::PathBuf>,
 
+    /// The format of the configuration file: yaml, toml or json. By
+    /// default this is detected from the file's extension.
+    #[structopt(long = "config-format")]
+    pub(crate) config_format: Option<ConfigFormat>,
+
+    /// Use the specified directory as the floki root, which is mounted
+    /// into the container. Takes precedence over `root` in the
+    /// configuration file.
+    #[structopt(long = "root")]
+    pub(crate) root: Option<path::PathBuf>,
+
+    /// Set a variable for templates, available as `vars.KEY`. Dotted keys
+    /// set nested values, e.g. `--set image.tag=latest`. May be repeated.
+    #[structopt(long = "set", value_name = "KEY=VALUE", number_of_values = 1)]
+    pub(crate) set: Vec<TemplateVar>,
+
+    /// Load variables for templates from a YAML, TOML or JSON file. May
+    /// be repeated, with later files taking precedence, and `--set`
+    /// taking precedence over all of them.
+    #[structopt(long = "values", value_name = "FILE", number_of_values = 1)]
+    pub(crate) values: Vec<path::PathBuf>,
+
+    /// Directories to find templates included by the configuration file
+    /// in, separated like $PATH. These are searched after the directory
+    /// containing the configuration file.
+    #[structopt(
+        long = "template-path",
+        env = "FLOKI_TEMPLATE_PATH",
+        parse(from_os_str)
+    )]
+    pub(crate) template_path: Option<std::ffi::OsString>,
+
+    /// Allow templates to read files in this directory, as well as in the
+    /// project. May be repeated.
+    #[structopt(long = "allow-template-path", value_name = "DIR", number_of_values = 1)]
+    pub(crate) allow_template_path: Vec<path::PathBuf>,
+
+    /// Don't apply the defaults from the user configuration file
+    /// ($XDG_CONFIG_HOME/floki/config.yaml).
+    #[structopt(long = "no-user-config")]
+    pub(crate) no_user_config: bool,
+
     /// Deprecated, and no longer has any effect.
     #[structopt(long = "local", short = "l", hidden = true)]
     pub(crate) local: bool,
@@ -44,6 +209,95 @@ pub(crate) struct Cli {
     #[structopt(short = "v", parse(from_occurrences))]
     pub(crate) verbosity: u8,
 
+    /// Allocate a TTY for the container (the default when both stdin
+    /// and stdout are terminals)
+    #[structopt(long = "tty", overrides_with = "no-tty")]
+    tty: bool,
+
+    /// Don't allocate a TTY for the container, keeping stdout and
+    /// stderr separate
+    #[structopt(long = "no-tty", overrides_with = "tty")]
+    no_tty: bool,
+
+    /// Keep the container's stdin attached (the default)
+    #[structopt(long = "interactive", overrides_with = "no-interactive")]
+    interactive: bool,
+
+    /// Don't attach the container's stdin
+    #[structopt(long = "no-interactive", overrides_with = "interactive")]
+    no_interactive: bool,
+
     #[structopt(subcommand)]
     pub(crate) subcommand: Option<Subcommand>,
 }
+
+impl Cli {
+    /// Whether a TTY was explicitly requested or refused
+    pub(crate) fn tty(&self) -> Option<bool> {
+        flag_pair(self.tty, self.no_tty)
+    }
+
+    /// Whether attaching stdin was explicitly requested or refused
+    pub(crate) fn interactive(&self) -> Option<bool> {
+        flag_pair(self.interactive, self.no_interactive)
+    }
+}
+
+fn flag_pair(enable: bool, disable: bool) -> Option<bool> {
+    match (enable, disable) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_run_passes_through_flags() {
+        let cli = Cli::from_iter(&["floki", "run", "--no-shell", "ls", "-la", "--color"]);
+        match cli.subcommand {
+            Some(Subcommand::Run { no_shell, command }) => {
+                assert!(no_shell);
+                assert_eq!(command, vec!["ls", "-la", "--color"]);
+            }
+            other => panic!("expected run subcommand, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_tty_flags() {
+        let cli = Cli::from_iter(&["floki", "--tty", "--no-tty", "--no-interactive"]);
+        assert_eq!(cli.tty(), Some(false));
+        assert_eq!(cli.interactive(), Some(false));
+
+        let cli = Cli::from_iter(&["floki"]);
+        assert_eq!(cli.tty(), None);
+        assert_eq!(cli.interactive(), None);
+    }
+
+    #[test]
+    fn test_template_vars_flags() {
+        let cli = Cli::from_iter(&[
+            "floki", "--set", "a=1", "--values", "ci.yaml", "--set", "b.c=2", "render",
+        ]);
+        assert_eq!(
+            cli.set,
+            vec!["a=1".parse().unwrap(), "b.c=2".parse().unwrap()]
+        );
+        assert_eq!(cli.values, vec![path::PathBuf::from("ci.yaml")]);
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("30d"), Ok(Duration::from_secs(30 * 86400)));
+        assert_eq!(parse_duration("12h"), Ok(Duration::from_secs(12 * 3600)));
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert!(parse_duration("30").is_err());
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("999999999999999999w").is_err());
+    }
+}
@@ -8,9 +8,12 @@ use std::process::{Command, Stdio};
 pub struct DockerCommandBuilder {
     name: String,
     volumes: Vec<OsString>,
+    mounts: Vec<OsString>,
     environment: Vec<OsString>,
     switches: Vec<OsString>,
     image: String,
+    tty: bool,
+    interactive: bool,
 }
 
 #[derive(Debug)]
@@ -51,6 +54,7 @@ impl DockerCommandBuilder {
         let mut command = Command::new("docker")
             .args(self.base_args())
             .args(self.build_volume_switches())
+            .args(self.build_mount_switches())
             .args(self.build_environment_switches())
             .args(self.build_docker_switches())
             .arg(&self.image)
@@ -83,6 +87,7 @@ impl DockerCommandBuilder {
             .args(["run", "--rm"])
             .args(["--name", &self.name])
             .args(self.build_volume_switches())
+            .args(self.build_mount_switches())
             .args(self.build_environment_switches())
             .args(self.build_docker_switches())
             .arg("-d")
@@ -113,9 +118,12 @@ impl DockerCommandBuilder {
         DockerCommandBuilder {
             name: uuid::Uuid::new_v4().to_string(),
             volumes: Vec::new(),
+            mounts: Vec::new(),
             environment: Vec::new(),
             switches: Vec::new(),
             image: image.into(),
+            tty: false,
+            interactive: false,
         }
     }
 
@@ -129,6 +137,11 @@ impl DockerCommandBuilder {
         self
     }
 
+    pub fn add_mount<S: AsRef<OsStr>>(mut self, spec: S) -> Self {
+        self.mounts.push(spec.as_ref().into());
+        self
+    }
+
     pub fn add_environment<V: AsRef<OsStr>, B: AsRef<OsStr>>(mut self, var: V, bind: B) -> Self {
         self.environment.push("-e".into());
         self.environment.push(Self::environment_mapping(var, bind));
@@ -140,6 +153,16 @@ impl DockerCommandBuilder {
         self
     }
 
+    pub fn set_tty(mut self, tty: bool) -> Self {
+        self.tty = tty;
+        self
+    }
+
+    pub fn set_interactive(mut self, interactive: bool) -> Self {
+        self.interactive = interactive;
+        self
+    }
+
     pub fn set_working_directory<S: AsRef<OsStr>>(self, directory: S) -> Self {
         let mut cmd = self;
         cmd = cmd.add_docker_switch("-w");
@@ -156,6 +179,15 @@ impl DockerCommandBuilder {
         switches
     }
 
+    fn build_mount_switches(&self) -> Vec<&OsStr> {
+        let mut switches = Vec::new();
+        for mount in self.mounts.iter() {
+            switches.push("--mount".as_ref());
+            switches.push(mount.as_os_str());
+        }
+        switches
+    }
+
     fn volume_mapping(src: &path::Path, dst: &path::Path) -> OsString {
         let mut mapping = src.to_path_buf().into_os_string();
         mapping.push(":");
@@ -179,8 +211,11 @@ impl DockerCommandBuilder {
     }
 
     fn base_args(&self) -> Vec<&OsStr> {
-        let mut base_args: Vec<&OsStr> = vec!["run".as_ref(), "--rm".as_ref(), "-t".as_ref()];
-        if atty::is(atty::Stream::Stdout) && atty::is(atty::Stream::Stdin) {
+        let mut base_args: Vec<&OsStr> = vec!["run".as_ref(), "--rm".as_ref()];
+        if self.tty {
+            base_args.push("-t".as_ref());
+        }
+        if self.interactive {
             base_args.push("-i".as_ref());
         }
         base_args
@@ -207,3 +242,20 @@ pub fn enable_docker_in_docker(
         .add_docker_switch(format!("{}:floki-docker", dind.name()))
         .add_environment("DOCKER_HOST", "tcp://floki-docker:2375"))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_base_args() {
+        let cmd = DockerCommandBuilder::new("image");
+        assert_eq!(cmd.base_args(), vec!["run", "--rm"]);
+
+        let cmd = cmd.set_tty(true).set_interactive(true);
+        assert_eq!(cmd.base_args(), vec!["run", "--rm", "-t", "-i"]);
+
+        let cmd = cmd.set_tty(false);
+        assert_eq!(cmd.base_args(), vec!["run", "--rm", "-i"]);
+    }
+}
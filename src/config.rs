@@ -1,7 +1,17 @@
 /// Configuration file format for floki
+use crate::diagnostics::{
+    deserialize_untagged, single_key, unknown_key, ConfigDiagnostic, Variant,
+};
 use crate::errors::FlokiError;
+use crate::files::{file_function, sha256, FileFunction};
+use crate::git::{git_function, GitQuery};
 use crate::image;
-use serde::{Deserialize, Serialize};
+use crate::merge::{as_object, override_value, MergedConfig};
+use crate::sandbox::{access_denied, Sandbox};
+use crate::templates::referenced_templates;
+use schemars::JsonSchema;
+use serde::de::{DeserializeOwned, Error as _};
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_yaml::{Mapping as YamlMapping, Value as YamlValue};
 use tera::from_value;
 use tera::Context;
@@ -9,9 +19,10 @@ use tera::Tera;
 
 use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::path::{Path, PathBuf};
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, JsonSchema)]
 #[serde(untagged)]
 pub(crate) enum Shell {
     Shell(String),
@@ -40,7 +51,40 @@ impl Default for Shell {
     }
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+impl<'de> Deserialize<'de> for Shell {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct TwoShell {
+            inner: String,
+            outer: String,
+        }
+
+        deserialize_untagged(
+            deserializer,
+            "shell",
+            &[
+                Variant {
+                    description: "a shell such as `bash`",
+                    keys: &[],
+                    parse: |v| serde_yaml::from_value(v).map(Shell::Shell),
+                },
+                Variant {
+                    description: "a map with `inner` and `outer` shells",
+                    keys: &["inner", "outer"],
+                    parse: |v| {
+                        serde_yaml::from_value(v.clone())
+                            .map(|TwoShell { inner, outer }| Shell::TwoShell { inner, outer })
+                            // Other keys are ignored, but are likely the
+                            // cause of a problem, e.g. a misspelt `outer`.
+                            .map_err(|e| unknown_key(&v, &["inner", "outer"]).unwrap_or(e))
+                    },
+                },
+            ],
+        )
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, JsonSchema)]
 #[serde(untagged)]
 pub(crate) enum DindConfig {
     Toggle(bool),
@@ -53,7 +97,162 @@ impl Default for DindConfig {
     }
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+impl<'de> Deserialize<'de> for DindConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_untagged(
+            deserializer,
+            "dind setting",
+            &[
+                Variant {
+                    description: "`true` or `false`",
+                    keys: &[],
+                    parse: |v| serde_yaml::from_value(v).map(DindConfig::Toggle),
+                },
+                Variant {
+                    description: "a map with the dind `image`",
+                    keys: &["image"],
+                    parse: |v| single_key(v, "image").map(|image| DindConfig::Image { image }),
+                },
+            ],
+        )
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Copy, Clone, Default, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum VolumeBackend {
+    /// Back the volume with a directory under the floki workspace
+    #[default]
+    Host,
+    /// Back the volume with a named docker volume
+    Docker,
+}
+
+#[derive(Debug, PartialEq, Serialize, JsonSchema)]
+#[serde(untagged)]
+/// Initial content for a newly created floki volume
+pub(crate) enum Seed {
+    /// Copy the content of a directory on the host
+    Directory { directory: PathBuf },
+    /// Extract a (possibly compressed) tarball from the host
+    Tarball { tarball: PathBuf },
+    /// Copy the content of the mount path from the image
+    Image { image: bool },
+}
+
+impl<'de> Deserialize<'de> for Seed {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_untagged(
+            deserializer,
+            "seed",
+            &[
+                Variant {
+                    description: "a map with a `directory` to copy",
+                    keys: &["directory"],
+                    parse: |v| {
+                        single_key(v, "directory").map(|directory| Seed::Directory { directory })
+                    },
+                },
+                Variant {
+                    description: "a map with a `tarball` to extract",
+                    keys: &["tarball"],
+                    parse: |v| single_key(v, "tarball").map(|tarball| Seed::Tarball { tarball }),
+                },
+                Variant {
+                    description: "a map with `image: true`",
+                    keys: &["image"],
+                    parse: |v| single_key(v, "image").map(|image| Seed::Image { image }),
+                },
+            ],
+        )
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Copy, Clone, JsonSchema)]
+#[serde(into = "String")]
+#[schemars(with = "OwnerSchema")]
+/// The owner of a floki volume's content
+pub(crate) enum Owner {
+    /// The user running floki
+    User,
+    /// An explicit user and group id
+    Id { uid: u32, gid: u32 },
+}
+
+impl Owner {
+    /// The user and group ids of the owner, given those of the host user
+    pub(crate) fn ids(&self, host_uid: u32, host_gid: u32) -> (u32, u32) {
+        match *self {
+            Owner::User => (host_uid, host_gid),
+            Owner::Id { uid, gid } => (uid, gid),
+        }
+    }
+}
+
+/// How an owner is written: `user`, `UID` or `UID:GID`, where a bare user
+/// id may also be a number
+#[derive(JsonSchema)]
+#[serde(untagged)]
+#[allow(dead_code)]
+enum OwnerSchema {
+    Id(u32),
+    Owner(String),
+}
+
+impl<'de> Deserialize<'de> for Owner {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // Only YAML reads a bare user id as a string.
+        let owner = match YamlValue::deserialize(deserializer)? {
+            YamlValue::String(owner) => owner,
+            YamlValue::Number(id) => id.to_string(),
+            other => {
+                return Err(D::Error::custom(format!(
+                    "invalid owner: expected 'user', 'UID' or 'UID:GID', found {}",
+                    serde_yaml::to_string(&other).unwrap_or_default().trim()
+                )))
+            }
+        };
+        Owner::try_from(owner).map_err(D::Error::custom)
+    }
+}
+
+impl TryFrom<String> for Owner {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        if value == "user" {
+            return Ok(Owner::User);
+        }
+        let invalid = || format!("invalid owner '{value}' - expected 'user', 'UID' or 'UID:GID'");
+        let (uid, gid) = match value.split_once(':') {
+            Some((uid, gid)) => (uid, gid),
+            None => (value.as_str(), value.as_str()),
+        };
+        Ok(Owner::Id {
+            uid: uid.parse().map_err(|_| invalid())?,
+            gid: gid.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl std::str::FromStr for Owner {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::try_from(s.to_string())
+    }
+}
+
+impl From<Owner> for String {
+    fn from(owner: Owner) -> Self {
+        match owner {
+            Owner::User => "user".into(),
+            Owner::Id { uid, gid } => format!("{uid}:{gid}"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Default, JsonSchema)]
 /// The Volume structure captures configuration for floki volumes
 pub(crate) struct Volume {
     #[serde(default)]
@@ -64,9 +263,56 @@ pub(crate) struct Volume {
     /// The mount path is the path at which the volume is mounted
     /// inside the floki container.
     pub(crate) mount: PathBuf,
+    #[serde(default)]
+    /// Where the volume's content is stored. Docker volumes are
+    /// managed by the docker daemon, so work with rootless setups and
+    /// remote daemons which can't see the host's floki workspace.
+    pub(crate) backend: VolumeBackend,
+    #[serde(default)]
+    /// Initial content for the volume, applied once when the volume
+    /// is first created.
+    pub(crate) seed: Option<Seed>,
+    #[serde(default)]
+    /// Who should own the volume's content when it is created.
+    pub(crate) owner: Option<Owner>,
+    #[serde(default)]
+    /// Reset ownership of the volume's content to the owner (or the
+    /// user running floki) after each run.
+    pub(crate) fix_ownership: bool,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize, Copy, Clone)]
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Copy, Clone, Default, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum MountType {
+    #[default]
+    Bind,
+    Tmpfs,
+    Volume,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+/// The Mount structure captures configuration for mounting host paths,
+/// tmpfs filesystems or docker volumes into the floki container
+pub(crate) struct Mount {
+    #[serde(default)]
+    /// For bind mounts, the path on the host (which may begin with `~`
+    /// and reference environment variables). For volume mounts, the
+    /// name of the docker volume. Not used for tmpfs mounts.
+    pub(crate) source: Option<String>,
+    /// The path at which the mount appears inside the floki container.
+    pub(crate) target: PathBuf,
+    #[serde(default)]
+    /// Mount the source read-only.
+    pub(crate) readonly: bool,
+    #[serde(default)]
+    /// Skip the mount (rather than failing) if the host path is missing.
+    pub(crate) optional: bool,
+    #[serde(default, rename = "type")]
+    pub(crate) mount_type: MountType,
+}
+
+#[derive(Debug, PartialEq, Serialize, Copy, Clone, JsonSchema)]
 #[serde(untagged)]
 pub(crate) enum Entrypoint {
     Suppress { suppress: bool },
@@ -87,35 +333,278 @@ impl Default for Entrypoint {
     }
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+impl<'de> Deserialize<'de> for Entrypoint {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_untagged(
+            deserializer,
+            "entrypoint",
+            &[Variant {
+                description: "a map with a `suppress` key",
+                keys: &["suppress"],
+                parse: |v| {
+                    single_key(v, "suppress").map(|suppress| Entrypoint::Suppress { suppress })
+                },
+            }],
+        )
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub(crate) struct FlokiConfig {
+    /// The image to run: an image name, or a way of building or
+    /// finding one.
     pub(crate) image: image::Image,
     #[serde(default)]
+    /// Commands run in the container before the shell or command.
     pub(crate) init: Vec<String>,
     #[serde(default)]
+    /// The shell to use, or separate inner and outer shells.
     pub(crate) shell: Shell,
+    #[serde(default)]
+    /// The directory mounted into the container, relative to the
+    /// configuration file.
+    pub(crate) root: Option<PathBuf>,
     #[serde(default = "default_mount")]
+    /// Where the floki root is mounted inside the container.
     pub(crate) mount: PathBuf,
     #[serde(default)]
+    /// The working directory inside the container. Relative paths are
+    /// relative to the mount.
+    pub(crate) workdir: Option<PathBuf>,
+    #[serde(default)]
+    /// Extra switches passed to `docker run`.
     pub(crate) docker_switches: Vec<String>,
     #[serde(default)]
-    pub(crate) forward_ssh_agent: bool,
+    /// Forward the host's SSH agent into the container.
+    pub(crate) forward_ssh_agent: Option<bool>,
     #[serde(default)]
+    /// Run a docker-in-docker container alongside this one.
     pub(crate) dind: DindConfig,
     #[serde(default)]
-    pub(crate) forward_user: bool,
+    /// Run the container as the host user.
+    pub(crate) forward_user: Option<bool>,
     #[serde(default)]
+    /// Named volumes which persist between runs.
     pub(crate) volumes: BTreeMap<String, Volume>,
     #[serde(default)]
+    /// Host paths, tmpfs filesystems and docker volumes to mount.
+    pub(crate) mounts: Vec<Mount>,
+    #[serde(default)]
+    /// Control the image's entrypoint.
     pub(crate) entrypoint: Entrypoint,
+    #[serde(default)]
+    /// Keys from the user configuration file to ignore.
+    pub(crate) ignore_user_config: Vec<UserConfigKey>,
+}
+
+/// Per-user defaults, read from the user's floki configuration file and
+/// applied to every project. Only a restricted set of keys is allowed so
+/// that projects stay reproducible.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Default, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct UserConfig {
+    /// Directories outside each project which templates may read files
+    /// from, relative to the user configuration file
+    #[serde(default)]
+    pub(crate) allow_template_paths: Vec<PathBuf>,
+    #[serde(default)]
+    pub(crate) forward_ssh_agent: Option<bool>,
+    #[serde(default)]
+    pub(crate) forward_user: Option<bool>,
+    #[serde(default)]
+    pub(crate) docker_switches: Vec<String>,
+    #[serde(default)]
+    pub(crate) mounts: Vec<Mount>,
+}
+
+/// The keys of the user configuration, which a project can choose to ignore
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Copy, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum UserConfigKey {
+    ForwardSshAgent,
+    ForwardUser,
+    DockerSwitches,
+    Mounts,
+}
+
+/// The formats a floki configuration file can be written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ConfigFormat {
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl ConfigFormat {
+    /// Detect the format of a configuration file from its extension,
+    /// defaulting to YAML
+    pub(crate) fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => ConfigFormat::Toml,
+            Some("json") => ConfigFormat::Json,
+            _ => ConfigFormat::Yaml,
+        }
+    }
+
+    /// Parse a document in this format
+    pub(crate) fn parse<T: DeserializeOwned>(
+        &self,
+        content: &str,
+        name: &str,
+    ) -> Result<T, FlokiError> {
+        self.parse_template(content, content, name)
+    }
+
+    /// Parse a rendered template in this format. Problems are reported
+    /// with their position in the rendered document, mapped back to the
+    /// template where possible.
+    pub(crate) fn parse_template<T: DeserializeOwned>(
+        &self,
+        template: &str,
+        content: &str,
+        name: &str,
+    ) -> Result<T, FlokiError> {
+        let diagnostic = match self {
+            ConfigFormat::Yaml => {
+                let error = match serde_path_to_error::deserialize(
+                    serde_yaml::Deserializer::from_str(content),
+                ) {
+                    Ok(parsed) => return Ok(parsed),
+                    Err(error) => error,
+                };
+                let path = error.path().to_string();
+                let error = error.into_inner();
+                let mut message = error.to_string();
+                let mut reported = None;
+                if let Some(location) = error.location() {
+                    let suffix =
+                        format!(" at line {} column {}", location.line(), location.column());
+                    message = message
+                        .strip_suffix(&suffix)
+                        .unwrap_or(&message)
+                        .to_string();
+                    // serde_yaml prefixes the path it reports the location
+                    // for. Values it had to buffer, such as untagged enums,
+                    // are reported at an enclosing map (or the start of the
+                    // document), so only trust the location for the full path.
+                    let (reported_path, rest) = match message.split_once(": ") {
+                        Some((prefix, rest)) if path.starts_with(prefix) => (prefix, rest),
+                        _ => ("", message.as_str()),
+                    };
+                    if reported_path == path && location.index() > 0 {
+                        reported = Some((location.line(), location.column()));
+                    }
+                    message = rest.to_string();
+                }
+                let diagnostic = ConfigDiagnostic::new(name, Some(path.clone()), message);
+                // Problems inside a variant map are reported further in.
+                let reported = reported.filter(|_| diagnostic.path.as_ref() == Some(&path));
+                diagnostic.at_yaml(reported, template, content)
+            }
+            ConfigFormat::Toml => {
+                let (path, error) = match toml::Deserializer::parse(content) {
+                    Ok(deserializer) => match serde_path_to_error::deserialize(deserializer) {
+                        Ok(parsed) => return Ok(parsed),
+                        Err(error) => (error.path().to_string(), error.into_inner()),
+                    },
+                    Err(error) => (String::new(), error),
+                };
+                let diagnostic =
+                    ConfigDiagnostic::new(name, Some(path), error.message().to_string());
+                match error.span() {
+                    Some(span) => {
+                        let before = &content[..span.start];
+                        let line = before.matches('\n').count() + 1;
+                        let column = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+                        diagnostic.at((line, column), template, content)
+                    }
+                    None => diagnostic,
+                }
+            }
+            ConfigFormat::Json => {
+                let mut deserializer = serde_json::Deserializer::from_str(content);
+                let (path, error) = match serde_path_to_error::deserialize(&mut deserializer) {
+                    Ok(parsed) => match deserializer.end() {
+                        Ok(()) => return Ok(parsed),
+                        Err(error) => (String::new(), error),
+                    },
+                    Err(error) => (error.path().to_string(), error.into_inner()),
+                };
+                let suffix = format!(" at line {} column {}", error.line(), error.column());
+                let message = error.to_string();
+                let message = message
+                    .strip_suffix(&suffix)
+                    .unwrap_or(&message)
+                    .to_string();
+                let diagnostic = ConfigDiagnostic::new(name, Some(path), message);
+                if error.line() > 0 {
+                    diagnostic.at((error.line(), error.column().max(1)), template, content)
+                } else {
+                    diagnostic
+                }
+            }
+        };
+        Err(FlokiError::ProblemParsingConfig {
+            diagnostic: Box::new(diagnostic),
+        })
+    }
+
+    /// Write a value as a document in this format
+    pub(crate) fn serialize<T: Serialize>(&self, value: &T) -> Result<String, FlokiError> {
+        let converted = match self {
+            ConfigFormat::Yaml => serde_yaml::to_string(value).map_err(|e| e.to_string()),
+            ConfigFormat::Toml => toml::to_string_pretty(value).map_err(|e| e.to_string()),
+            ConfigFormat::Json => serde_json::to_string_pretty(value).map_err(|e| e.to_string()),
+        };
+        converted.map_err(|error| FlokiError::ProblemConvertingConfig {
+            format: *self,
+            error,
+        })
+    }
+
+    /// Convert a document from this format to another
+    pub(crate) fn convert(
+        &self,
+        content: &str,
+        name: &str,
+        to: ConfigFormat,
+    ) -> Result<String, FlokiError> {
+        let value: serde_json::Value = self.parse(content, name)?;
+        to.serialize(&value)
+    }
+}
+
+impl std::str::FromStr for ConfigFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "yaml" | "yml" => Ok(ConfigFormat::Yaml),
+            "toml" => Ok(ConfigFormat::Toml),
+            "json" => Ok(ConfigFormat::Json),
+            _ => Err(format!(
+                "unknown configuration format '{s}' - use yaml, toml or json"
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConfigFormat::Yaml => write!(f, "YAML"),
+            ConfigFormat::Toml => write!(f, "TOML"),
+            ConfigFormat::Json => write!(f, "JSON"),
+        }
+    }
 }
 
 fn default_mount() -> PathBuf {
     PathBuf::from("/src")
 }
 
-fn path_from_args(args: &HashMap<String, tera::Value>) -> tera::Result<String> {
+pub(crate) fn path_from_args(args: &HashMap<String, tera::Value>) -> tera::Result<String> {
     let file = match args.get("file") {
         Some(file) => file,
         None => return Err("file parameter is required".into()),
@@ -129,6 +618,78 @@ enum LoaderType {
     Toml,
 }
 
+/// How the `yaml` loader treats tags such as GitLab's `!reference`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum YamlTags {
+    /// Discard tags, keeping the tagged values
+    Strip,
+    /// Expose tagged values as maps of their `tag` and `value`
+    Keep,
+    /// Resolve GitLab `!reference` tags, and discard any others
+    Resolve,
+}
+
+/// GitLab allows `!reference` tags to be nested this deep
+const MAX_REFERENCE_DEPTH: usize = 10;
+
+fn yaml_tags_from_args(args: &HashMap<String, tera::Value>) -> tera::Result<YamlTags> {
+    match args.get("tags").and_then(|t| t.as_str()) {
+        None if !args.contains_key("tags") => Ok(YamlTags::Strip),
+        Some("strip") => Ok(YamlTags::Strip),
+        Some("keep") => Ok(YamlTags::Keep),
+        Some("resolve") => Ok(YamlTags::Resolve),
+        _ => Err(format!(
+            "tags must be \"strip\", \"keep\" or \"resolve\", not {}",
+            args["tags"]
+        )
+        .into()),
+    }
+}
+
+/// Load the first document in a YAML file, handling its tags as requested
+pub(crate) fn load_yaml(contents: &str, tags: YamlTags) -> Result<YamlValue, String> {
+    let raw = match serde_yaml::Deserializer::from_str(contents).next() {
+        Some(document) => YamlValue::deserialize(document)
+            .map_err(|err| format!("Failed to parse file as YAML: {err}"))?,
+        None => YamlValue::Null,
+    };
+    match tags {
+        YamlTags::Strip => Ok(strip_yaml_tags(&raw)),
+        YamlTags::Keep => Ok(keep_yaml_tags(&raw)),
+        YamlTags::Resolve => resolve_yaml_references(&raw, &raw, 0),
+    }
+}
+
+/// Find the value at a dotted key, such as `variables.IMAGE` or `jobs.0`
+pub(crate) fn value_at_key<'a>(value: &'a YamlValue, key: &str) -> Option<&'a YamlValue> {
+    key.split('.').try_fold(value, |value, key| {
+        // Yaml sequences and maps with scalar keys can both be indexed by
+        // usize, so heuristically prefer a usize index to a &str index.
+        match key.parse::<usize>() {
+            Ok(index) => value.get(index).or_else(|| value.get(key)),
+            Err(_) => value.get(key),
+        }
+    })
+}
+
+/// Select the value at the `key` argument of a loader, if given, falling
+/// back to the `default` argument if the key isn't found
+fn value_from_args(
+    args: &HashMap<String, tera::Value>,
+    document: YamlValue,
+) -> tera::Result<YamlValue> {
+    let key = match args.get("key") {
+        Some(key) => from_value::<String>(key.clone())?,
+        None => return Ok(document),
+    };
+    match (value_at_key(&document, &key), args.get("default")) {
+        (Some(value), _) => Ok(value.clone()),
+        (None, Some(default)) => serde_yaml::to_value(default)
+            .map_err(|err| format!("Failed to convert default value: {err}").into()),
+        (None, None) => Err(format!("Failed to find the key '{key}'").into()),
+    }
+}
+
 fn strip_yaml_tags(value: &YamlValue) -> YamlValue {
     match value {
         // For tagged values, we just return the inner value.
@@ -153,42 +714,213 @@ fn strip_yaml_tags(value: &YamlValue) -> YamlValue {
     }
 }
 
-fn makeloader(path: &Path, loader: LoaderType) -> impl tera::Function {
-    // Get the dirname of the Path given (if a file), or just the directory.
-    let directory = if path.is_file() {
+fn keep_yaml_tags(value: &YamlValue) -> YamlValue {
+    match value {
+        // Tagged values become a map of the tag and the value.
+        YamlValue::Tagged(tagged) => {
+            let mut kept = YamlMapping::new();
+            kept.insert("tag".into(), tagged.tag.to_string().into());
+            kept.insert("value".into(), keep_yaml_tags(&tagged.value));
+            YamlValue::Mapping(kept)
+        }
+        YamlValue::Sequence(items) => {
+            YamlValue::Sequence(items.iter().map(keep_yaml_tags).collect())
+        }
+        YamlValue::Mapping(map) => YamlValue::Mapping(
+            map.iter()
+                .map(|(key, value)| (keep_yaml_tags(key), keep_yaml_tags(value)))
+                .collect(),
+        ),
+        _ => value.clone(),
+    }
+}
+
+/// Resolve GitLab `!reference [job, key, ...]` tags by looking up the
+/// path within the same document. As in GitLab, a reference to a sequence
+/// within a sequence is spliced into it, so scripts can be combined.
+fn resolve_yaml_references(
+    value: &YamlValue,
+    document: &YamlValue,
+    depth: usize,
+) -> Result<YamlValue, String> {
+    if depth > MAX_REFERENCE_DEPTH {
+        return Err(format!(
+            "!reference tags are nested more than {MAX_REFERENCE_DEPTH} deep - do they refer to each other?"
+        ));
+    }
+    match value {
+        YamlValue::Tagged(tagged) if tagged.tag == "reference" => {
+            let referenced = follow_yaml_reference(&tagged.value, document)?;
+            resolve_yaml_references(referenced, document, depth + 1)
+        }
+        YamlValue::Tagged(tagged) => resolve_yaml_references(&tagged.value, document, depth),
+        YamlValue::Sequence(items) => {
+            let mut resolved = Vec::with_capacity(items.len());
+            for item in items {
+                let is_reference =
+                    matches!(item, YamlValue::Tagged(tagged) if tagged.tag == "reference");
+                match resolve_yaml_references(item, document, depth)? {
+                    YamlValue::Sequence(spliced) if is_reference => resolved.extend(spliced),
+                    other => resolved.push(other),
+                }
+            }
+            Ok(YamlValue::Sequence(resolved))
+        }
+        YamlValue::Mapping(map) => {
+            let mut resolved = YamlMapping::with_capacity(map.len());
+            for (key, value) in map {
+                resolved.insert(
+                    strip_yaml_tags(key),
+                    resolve_yaml_references(value, document, depth)?,
+                );
+            }
+            Ok(YamlValue::Mapping(resolved))
+        }
+        _ => Ok(value.clone()),
+    }
+}
+
+/// Find the value a `!reference` path refers to
+fn follow_yaml_reference<'a>(
+    path: &YamlValue,
+    document: &'a YamlValue,
+) -> Result<&'a YamlValue, String> {
+    let keys = match path {
+        YamlValue::Sequence(keys) if !keys.is_empty() => keys,
+        _ => {
+            return Err(format!(
+                "!reference must be a list of keys, not {}",
+                describe_yaml_value(path)
+            ))
+        }
+    };
+    let mut value = document;
+    for key in keys {
+        value = match key {
+            YamlValue::String(key) => value.get(key.as_str()),
+            YamlValue::Number(index) => index.as_u64().and_then(|i| value.get(i as usize)),
+            _ => None,
+        }
+        .ok_or_else(|| {
+            format!(
+                "!reference {}: {} not found",
+                describe_yaml_path(keys),
+                describe_yaml_value(key)
+            )
+        })?;
+    }
+    Ok(value)
+}
+
+/// Show a value as it would be written in YAML, on one line
+fn describe_yaml_value(value: &YamlValue) -> String {
+    serde_yaml::to_string(value)
+        .unwrap_or_default()
+        .trim()
+        .replace('\n', " ")
+}
+
+fn describe_yaml_path(keys: &[YamlValue]) -> String {
+    let keys = keys.iter().map(describe_yaml_value).collect::<Vec<_>>();
+    format!("[{}]", keys.join(", "))
+}
+
+/// Get the dirname of the Path given (if a file), or just the directory.
+fn template_directory(path: &Path) -> PathBuf {
+    if path.is_file() {
         path.parent().expect("File should have a parent directory")
     } else {
         path
     }
-    .to_path_buf();
+    .to_path_buf()
+}
+
+fn makeloader(path: &Path, loader: LoaderType, sandbox: Sandbox) -> impl tera::Function {
+    let directory = template_directory(path);
 
     Box::new(move |args: &HashMap<String, tera::Value>| {
         path_from_args(args)
             // Calculate the full path using the parent directory
             .map(|path| directory.join(path))
+            // Only read files the sandbox allows
+            .and_then(|full_path| sandbox.guard(&full_path).map(|_| full_path))
             // Read the file as a string
             .and_then(|full_path| std::fs::read_to_string(full_path).map_err(Into::into))
             // Parse the file using the relevant parser
             .and_then(|contents| match loader {
                 LoaderType::Yaml => {
-                    let raw: YamlValue = serde_yaml::from_str(&contents).map_err(|err| {
-                        tera::Error::msg(format!("Failed to parse file as YAML: {err}"))
-                    })?;
-                    let stripped = strip_yaml_tags(&raw);
-                    serde_yaml::from_value::<tera::Value>(stripped).map_err(|err| {
-                        tera::Error::msg(format!("Failed to convert YAML value: {err}"))
-                    })
+                    load_yaml(&contents, yaml_tags_from_args(args)?).map_err(tera::Error::msg)
+                }
+                _ if args.contains_key("tags") => {
+                    Err("tags is only supported when loading YAML".into())
                 }
                 LoaderType::Json => serde_json::from_str(&contents)
                     .map_err(|err| format!("Failed to parse file as JSON: {err}").into()),
                 LoaderType::Toml => toml::from_str(&contents)
                     .map_err(|err| format!("Failed to parse file as TOML: {err}").into()),
             })
+            // Select the requested key, the same way as an image's `yaml` key
+            .and_then(|document| value_from_args(args, document))
+            .and_then(|value| {
+                serde_yaml::from_value::<tera::Value>(value)
+                    .map_err(|err| format!("Failed to convert value: {err}").into())
+            })
     })
 }
 
+/// Information about floki and the host, available to templates as `floki`
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct FlokiVariables {
+    /// The floki root chosen by `--root` or the configuration file's
+    /// location (the `root` key is applied after rendering)
+    pub(crate) root: PathBuf,
+    /// The configuration file
+    pub(crate) config: PathBuf,
+    /// The directory floki was run from
+    pub(crate) current_directory: PathBuf,
+    /// The floki workspace, which holds volumes and other state
+    pub(crate) workspace: PathBuf,
+    /// The user running floki
+    pub(crate) user: HostUser,
+    /// The host architecture, as named by Rust (e.g. `x86_64`)
+    pub(crate) arch: String,
+    /// The host architecture, as named by docker (e.g. `amd64`)
+    pub(crate) docker_arch: String,
+    /// The host operating system (e.g. `linux`)
+    pub(crate) os: String,
+    /// The version of floki
+    pub(crate) version: String,
+}
+
+/// The user running floki
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct HostUser {
+    pub(crate) uid: u32,
+    pub(crate) gid: u32,
+    pub(crate) name: String,
+}
+
+/// Everything templates are rendered with, other than the environment
+/// variables
+#[derive(Debug, Default)]
+pub(crate) struct TemplateContext {
+    pub(crate) floki: FlokiVariables,
+    /// Variables from `--values` and `--set`
+    pub(crate) vars: serde_json::Map<String, serde_json::Value>,
+    /// Shared directories to find included templates in, after the
+    /// directory containing the template
+    pub(crate) template_path: Vec<PathBuf>,
+    /// Directories outside the project which templates may read, from
+    /// `--allow-template-path` and the user configuration
+    pub(crate) allowed_paths: Vec<PathBuf>,
+}
+
 // Renders a template from a given string.
-pub fn render_template(template: &str, source_filename: &Path) -> Result<String, FlokiError> {
+pub(crate) fn render_template(
+    template: &str,
+    source_filename: &Path,
+    template_context: &TemplateContext,
+) -> Result<String, FlokiError> {
     let template_path = source_filename.display().to_string();
     debug!("Rendering template: {template_path}");
 
@@ -201,15 +933,56 @@ pub fn render_template(template: &str, source_filename: &Path) -> Result<String,
     })?;
     debug!("Canonical path: {canonical_path:?}");
 
+    // Templates may only read files in the project, next to the template,
+    // on the shared template path, or in explicitly allowed directories.
+    let mut directories = vec![template_directory(&canonical_path)];
+    directories.extend(template_context.template_path.iter().cloned());
+    let sandbox = Sandbox::new(
+        directories
+            .iter()
+            .chain(std::iter::once(&template_context.floki.root))
+            .chain(&template_context.allowed_paths)
+            .cloned(),
+    );
+
     // Read the template using tera
     let mut tera = Tera::default();
 
     // Allow templates to load variables files as Values.
-    tera.register_function("yaml", makeloader(&canonical_path, LoaderType::Yaml));
-    tera.register_function("json", makeloader(&canonical_path, LoaderType::Json));
-    tera.register_function("toml", makeloader(&canonical_path, LoaderType::Toml));
+    for (name, loader) in [
+        ("yaml", LoaderType::Yaml),
+        ("json", LoaderType::Json),
+        ("toml", LoaderType::Toml),
+    ] {
+        tera.register_function(name, makeloader(&canonical_path, loader, sandbox.clone()));
+    }
+
+    // Allow templates to inspect and hash files next to the template.
+    for (name, function) in FileFunction::ALL {
+        tera.register_function(
+            name,
+            file_function(
+                template_directory(&canonical_path),
+                *function,
+                sandbox.clone(),
+            ),
+        );
+    }
+    tera.register_function("sha256", sha256);
 
-    tera.add_raw_template(&template_path, template)
+    // Allow templates to query the git repository containing the template.
+    for (name, query) in GitQuery::ALL {
+        tera.register_function(
+            name,
+            git_function(template_directory(&canonical_path), *query),
+        );
+    }
+
+    // Load the templates this one includes, imports or extends, from
+    // next to the template or the shared template path.
+    let mut templates = referenced_templates(&template_path, template, &directories, &sandbox)?;
+    templates.push((template_path.clone(), template.to_string()));
+    tera.add_raw_templates(templates)
         .map_err(|e| FlokiError::ProblemRenderingTemplate {
             name: template_path.clone(),
             error: e,
@@ -220,17 +993,29 @@ pub fn render_template(template: &str, source_filename: &Path) -> Result<String,
     let vars: HashMap<String, String> = std::env::vars().collect();
     let mut context = Context::new();
     context.insert("env", &vars);
+    context.insert("floki", &template_context.floki);
+    context.insert("vars", &template_context.vars);
 
     // Render the floki file to string using the context.
-    tera.render(&template_path, &context)
-        .map_err(|e| FlokiError::ProblemRenderingTemplate {
+    tera.render(&template_path, &context).map_err(|e| {
+        access_denied(&e).unwrap_or_else(|| FlokiError::ProblemRenderingTemplate {
             name: template_path.clone(),
             error: e,
         })
+    })
 }
 
 impl FlokiConfig {
-    pub fn render(file: &Path) -> Result<String, FlokiError> {
+    pub fn render(file: &Path, context: &TemplateContext) -> Result<String, FlokiError> {
+        Ok(Self::read_and_render(file, context)?.1)
+    }
+
+    /// Read a configuration file, returning both the template and the
+    /// rendered output
+    fn read_and_render(
+        file: &Path,
+        context: &TemplateContext,
+    ) -> Result<(String, String), FlokiError> {
         let content =
             std::fs::read_to_string(file).map_err(|e| FlokiError::ProblemOpeningConfigYaml {
                 name: file.display().to_string(),
@@ -238,21 +1023,71 @@ impl FlokiConfig {
             })?;
 
         // Render the template first before parsing it.
-        render_template(&content, file)
+        let output = render_template(&content, file, context)?;
+        Ok((content, output))
     }
 
-    pub fn from_file(file: &Path) -> Result<Self, FlokiError> {
-        debug!("Reading configuration file: {file:?}");
-
-        // Render the output from the configuration file before parsing.
-        let output = Self::render(file)?;
+    /// Read, render and parse a configuration file
+    fn parse_file<T: DeserializeOwned>(
+        file: &Path,
+        format: ConfigFormat,
+        context: &TemplateContext,
+    ) -> Result<T, FlokiError> {
+        let (template, output) = Self::read_and_render(file, context)?;
+        format.parse_template(&template, &output, &file.display().to_string())
+    }
 
-        // Parse the rendered floki file from the string.
-        let mut config: FlokiConfig =
-            serde_yaml::from_str(&output).map_err(|e| FlokiError::ProblemParsingConfigYaml {
-                name: file.display().to_string(),
-                error: e,
+    /// Render the configuration file and its override files and merge
+    /// them, remembering where each value came from
+    pub(crate) fn merge_files(
+        file: &Path,
+        format: ConfigFormat,
+        overrides: &[PathBuf],
+        context: &TemplateContext,
+    ) -> Result<MergedConfig, FlokiError> {
+        let parse = |file: &Path, format: ConfigFormat| -> Result<serde_json::Value, FlokiError> {
+            Ok(as_object(Self::parse_file(file, format, context)?))
+        };
+        let mut merged = MergedConfig::new(file, parse(file, format)?);
+        for override_file in overrides {
+            debug!("Merging override file: {override_file:?}");
+            let value: YamlValue = Self::parse_file(override_file, ConfigFormat::Yaml, context)?;
+            let value = override_value(value).map_err(|e| FlokiError::ProblemParsingConfig {
+                diagnostic: Box::new(ConfigDiagnostic::new(
+                    &override_file.display().to_string(),
+                    None,
+                    e.to_string(),
+                )),
             })?;
+            merged.merge(override_file, as_object(value));
+        }
+        Ok(merged)
+    }
+
+    pub fn from_file(
+        file: &Path,
+        format: ConfigFormat,
+        overrides: &[PathBuf],
+        context: &TemplateContext,
+    ) -> Result<Self, FlokiError> {
+        debug!("Reading {format} configuration file: {file:?}");
+
+        let mut config: FlokiConfig = if overrides.is_empty() {
+            // Render the configuration file and parse the output.
+            Self::parse_file(file, format, context)?
+        } else {
+            let merged = Self::merge_files(file, format, overrides, context)?;
+            serde_path_to_error::deserialize(merged.value).map_err(|error| {
+                FlokiError::ProblemParsingMergedConfig {
+                    names: std::iter::once(file)
+                        .chain(overrides.iter().map(PathBuf::as_path))
+                        .map(|f| format!("'{}'", f.display()))
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    error,
+                }
+            })?
+        };
 
         // Ensure the path to an external yaml file is correct.
         // If the image.yaml.path file is relative, then it should
@@ -281,6 +1116,41 @@ impl FlokiConfig {
 
         Ok(config)
     }
+
+    /// Apply the user's defaults underneath this configuration. Values set
+    /// in the project take precedence, user lists come before the project's,
+    /// and keys listed in `ignore_user_config` are left alone.
+    pub(crate) fn apply_user_config(&mut self, user: UserConfig) {
+        let ignored = self.ignore_user_config.clone();
+        let applies = |key| !ignored.contains(&key);
+
+        if applies(UserConfigKey::ForwardSshAgent) {
+            self.forward_ssh_agent = self.forward_ssh_agent.or(user.forward_ssh_agent);
+        }
+        if applies(UserConfigKey::ForwardUser) {
+            self.forward_user = self.forward_user.or(user.forward_user);
+        }
+        if applies(UserConfigKey::DockerSwitches) {
+            let project = std::mem::replace(&mut self.docker_switches, user.docker_switches);
+            self.docker_switches.extend(project);
+        }
+        if applies(UserConfigKey::Mounts) {
+            let project = std::mem::replace(&mut self.mounts, user.mounts);
+            self.mounts.extend(project);
+        }
+    }
+}
+
+impl UserConfig {
+    pub fn from_file(file: &Path, context: &TemplateContext) -> Result<Self, FlokiError> {
+        debug!("Reading user configuration file: {file:?}");
+        let (template, output) = FlokiConfig::read_and_render(file, context)?;
+        // An empty file (or one with only comments) sets no defaults.
+        if serde_yaml::from_str::<YamlValue>(&output).is_ok_and(|v| v.is_null()) {
+            return Ok(UserConfig::default());
+        }
+        ConfigFormat::Yaml.parse_template(&template, &output, &file.display().to_string())
+    }
 }
 
 #[cfg(test)]
@@ -370,19 +1240,304 @@ mod test {
         assert_eq!(actual.entrypoint.value(), None);
     }
 
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct TestVolumesConfig {
+        volumes: BTreeMap<String, Volume>,
+    }
+
+    #[test]
+    fn test_volume_seed_config() {
+        let yaml = "volumes:\n  a:\n    mount: /a\n    seed:\n      directory: ~/a\n  b:\n    mount: /b\n    seed:\n      tarball: b.tar.gz\n  c:\n    mount: /c\n    seed:\n      image: true";
+        let actual: TestVolumesConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(
+            actual.volumes["a"].seed,
+            Some(Seed::Directory {
+                directory: "~/a".into()
+            })
+        );
+        assert_eq!(
+            actual.volumes["b"].seed,
+            Some(Seed::Tarball {
+                tarball: "b.tar.gz".into()
+            })
+        );
+        assert_eq!(actual.volumes["c"].seed, Some(Seed::Image { image: true }));
+    }
+
+    #[test]
+    fn test_volume_owner_config() {
+        let yaml = "volumes:\n  a:\n    mount: /a\n    owner: user\n    fix_ownership: true\n  b:\n    mount: /b\n    owner: 1000:100\n  c:\n    mount: /c\n    owner: \"0\"";
+        let actual: TestVolumesConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(actual.volumes["a"].owner, Some(Owner::User));
+        assert!(actual.volumes["a"].fix_ownership);
+        assert_eq!(
+            actual.volumes["b"].owner,
+            Some(Owner::Id {
+                uid: 1000,
+                gid: 100
+            })
+        );
+        assert_eq!(
+            actual.volumes["c"].owner,
+            Some(Owner::Id { uid: 0, gid: 0 })
+        );
+        assert_eq!(actual.volumes["a"].owner.unwrap().ids(5, 6), (5, 6));
+
+        let invalid = "volumes:\n  a:\n    mount: /a\n    owner: somebody";
+        assert!(serde_yaml::from_str::<TestVolumesConfig>(invalid).is_err());
+    }
+
+    #[test]
+    fn test_volume_owner_json() -> Result<(), Box<dyn std::error::Error>> {
+        let json = r#"{"volumes": {"a": {"mount": "/a", "owner": 1000}, "b": {"mount": "/b", "owner": "1000:100"}}}"#;
+        let actual: TestVolumesConfig = serde_json::from_str(json)?;
+        assert_eq!(
+            actual.volumes["a"].owner,
+            Some(Owner::Id {
+                uid: 1000,
+                gid: 1000
+            })
+        );
+        assert_eq!(
+            actual.volumes["b"].owner,
+            Some(Owner::Id {
+                uid: 1000,
+                gid: 100
+            })
+        );
+
+        for invalid in [r#"-1"#, r#"true"#, r#""somebody""#] {
+            let json = format!(
+                r#"{{"volumes": {{"a": {{"mount": "/a", "owner": {}}}}}}}"#,
+                invalid
+            );
+            assert!(serde_json::from_str::<TestVolumesConfig>(&json).is_err());
+        }
+        Ok(())
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct TestMountsConfig {
+        mounts: Vec<Mount>,
+    }
+
+    #[test]
+    fn test_mounts_config() {
+        let yaml = "mounts:\n  - source: ~/.gitconfig\n    target: /home/build/.gitconfig\n    readonly: true\n  - target: /tmp\n    type: tmpfs";
+        let expected = TestMountsConfig {
+            mounts: vec![
+                Mount {
+                    source: Some("~/.gitconfig".into()),
+                    target: "/home/build/.gitconfig".into(),
+                    readonly: true,
+                    optional: false,
+                    mount_type: MountType::Bind,
+                },
+                Mount {
+                    source: None,
+                    target: "/tmp".into(),
+                    readonly: false,
+                    optional: false,
+                    mount_type: MountType::Tmpfs,
+                },
+            ],
+        };
+        let actual: TestMountsConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_config_formats_are_equivalent() -> Result<(), Box<dyn std::error::Error>> {
+        let yaml = "image: debian\nshell:\n  inner: bash\n  outer: sh\nvolumes:\n  cache:\n    mount: /cache\n";
+        let toml = "image = \"debian\"\n[shell]\ninner = \"bash\"\nouter = \"sh\"\n[volumes.cache]\nmount = \"/cache\"\n";
+        let json = r#"{"image": "debian", "shell": {"inner": "bash", "outer": "sh"}, "volumes": {"cache": {"mount": "/cache"}}}"#;
+
+        let from_yaml: FlokiConfig = ConfigFormat::Yaml.parse(yaml, "floki.yaml")?;
+        let from_toml: FlokiConfig = ConfigFormat::Toml.parse(toml, "floki.toml")?;
+        let from_json: FlokiConfig = ConfigFormat::Json.parse(json, "floki.json")?;
+
+        assert_eq!(from_yaml, from_toml);
+        assert_eq!(from_yaml, from_json);
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_format_detection() {
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("floki.toml")),
+            ConfigFormat::Toml
+        );
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("a/floki.json")),
+            ConfigFormat::Json
+        );
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("floki.yml")),
+            ConfigFormat::Yaml
+        );
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("config")),
+            ConfigFormat::Yaml
+        );
+    }
+
+    #[test]
+    fn test_config_format_convert() -> Result<(), Box<dyn std::error::Error>> {
+        let yaml = "image: debian\ninit:\n  - echo hi\n";
+        let toml = ConfigFormat::Yaml.convert(yaml, "floki.yaml", ConfigFormat::Toml)?;
+        assert_eq!(toml, "image = \"debian\"\ninit = [\"echo hi\"]\n");
+        let back = ConfigFormat::Toml.convert(&toml, "floki.toml", ConfigFormat::Yaml)?;
+        assert_eq!(back, "image: debian\ninit:\n- echo hi\n");
+        Ok(())
+    }
+
+    fn user_config() -> Result<UserConfig, FlokiError> {
+        ConfigFormat::Yaml.parse(
+            "forward_ssh_agent: true\nforward_user: true\ndocker_switches:\n  - --network=host\nmounts:\n  - source: ~/.gitconfig\n    target: /root/.gitconfig\n",
+            "config.yaml",
+        )
+    }
+
+    #[test]
+    fn test_user_config_applies_under_project() -> Result<(), Box<dyn std::error::Error>> {
+        let yaml = "image: debian\nforward_user: false\ndocker_switches:\n  - --privileged\n";
+        let mut config: FlokiConfig = ConfigFormat::Yaml.parse(yaml, "floki.yaml")?;
+        config.apply_user_config(user_config()?);
+
+        assert_eq!(config.forward_ssh_agent, Some(true));
+        assert_eq!(config.forward_user, Some(false));
+        assert_eq!(
+            config.docker_switches,
+            vec!["--network=host", "--privileged"]
+        );
+        assert_eq!(config.mounts.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_user_config_ignored_keys() -> Result<(), Box<dyn std::error::Error>> {
+        let yaml = "image: debian\nignore_user_config:\n  - docker_switches\n  - mounts\n";
+        let mut config: FlokiConfig = ConfigFormat::Yaml.parse(yaml, "floki.yaml")?;
+        config.apply_user_config(user_config()?);
+
+        assert_eq!(config.forward_ssh_agent, Some(true));
+        assert!(config.docker_switches.is_empty());
+        assert!(config.mounts.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_user_config_rejects_project_keys() {
+        let parsed: Result<UserConfig, _> =
+            ConfigFormat::Yaml.parse("image: debian", "config.yaml");
+        assert!(parsed.is_err());
+    }
+
+    #[test]
+    fn test_variant_maps_ignore_other_keys() -> Result<(), Box<dyn std::error::Error>> {
+        let yaml = "image:\n  build:\n    name: foo\n  comment: built locally\nshell:\n  inner: bash\n  outer: sh\n  note: x\ndind:\n  image: docker:dind\n  note: x\n";
+        let config: FlokiConfig = ConfigFormat::Yaml.parse(yaml, "floki.yaml")?;
+        assert!(matches!(config.image, image::Image::Build { .. }));
+        assert_eq!(config.shell.inner_shell(), "bash");
+        assert_eq!(
+            config.dind,
+            DindConfig::Image {
+                image: "docker:dind".into()
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_diagnostics() {
+        let template = "image: debian\n{# comment #}\nshell:\n  inner: bash\n  outr: sh\n";
+        let rendered = "image: debian\n\nshell:\n  inner: bash\n  outr: sh\n";
+        match ConfigFormat::Yaml.parse_template::<FlokiConfig>(template, rendered, "floki.yaml") {
+            Err(FlokiError::ProblemParsingConfig { diagnostic }) => {
+                assert_eq!(diagnostic.path.as_deref(), Some("shell"));
+                assert_eq!(diagnostic.position, Some((5, 3)));
+                assert_eq!(diagnostic.snippet.as_deref(), Some("  outr: sh"));
+                assert_eq!(diagnostic.help.as_deref(), Some("did you mean `outer`?"));
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_untagged_enum_errors_explain_variants() {
+        let result: Result<FlokiConfig, _> =
+            ConfigFormat::Yaml.parse("image:\n  build:\n    nme: foo\n", "floki.yaml");
+        match result {
+            Err(FlokiError::ProblemParsingConfig { diagnostic }) => {
+                assert_eq!(diagnostic.path.as_deref(), Some("image.build"));
+                assert!(!diagnostic.message.contains("did not match any variant"));
+                assert!(
+                    diagnostic.position.is_some_and(|(line, _)| line > 1),
+                    "{:?}",
+                    diagnostic
+                );
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
     #[test]
     fn test_tera_render() -> Result<(), Box<dyn std::error::Error>> {
         let template = r#"{% set var = "test" %}image: {{ var }}"#;
-        let config = render_template(template, Path::new("floki.yaml"))?;
+        let config = render_template(
+            template,
+            Path::new("floki.yaml"),
+            &TemplateContext::default(),
+        )?;
         assert_eq!(config, "image: test");
         Ok(())
     }
 
+    #[test]
+    fn test_tera_render_floki_variables() -> Result<(), Box<dyn std::error::Error>> {
+        let context = TemplateContext {
+            floki: FlokiVariables {
+                root: "/src/project".into(),
+                docker_arch: "arm64".into(),
+                user: HostUser {
+                    uid: 1000,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let template =
+            "image: foo-{{ floki.docker_arch }}\nmount: {{ floki.root }}/{{ floki.user.uid }}";
+        let config = render_template(template, Path::new("floki.yaml"), &context)?;
+        assert_eq!(config, "image: foo-arm64\nmount: /src/project/1000");
+        Ok(())
+    }
+
+    #[test]
+    fn test_tera_render_vars() -> Result<(), Box<dyn std::error::Error>> {
+        let mut vars = serde_json::Map::new();
+        vars.insert("image".into(), serde_json::json!({"tag": "v2"}));
+        let context = TemplateContext {
+            vars,
+            ..Default::default()
+        };
+        let template =
+            "image: foo:{{ vars.image.tag }}{% if vars.debug is defined %}-debug{% endif %}";
+        let config = render_template(template, Path::new("floki.yaml"), &context)?;
+        assert_eq!(config, "image: foo:v2");
+        Ok(())
+    }
+
     #[test]
     fn test_tera_yamlload() -> Result<(), Box<dyn std::error::Error>> {
         let template =
             r#"{% set values = yaml(file="test_resources/values.yaml") %}shell: {{ values.foo }}"#;
-        let config = render_template(template, Path::new("floki.yaml"))?;
+        let config = render_template(
+            template,
+            Path::new("floki.yaml"),
+            &TemplateContext::default(),
+        )?;
         assert_eq!(config, "shell: bar");
         Ok(())
     }
@@ -391,7 +1546,11 @@ mod test {
     fn test_tera_jsonload() -> Result<(), Box<dyn std::error::Error>> {
         let template =
             r#"{% set values = json(file="test_resources/values.json") %}shell: {{ values.foo }}"#;
-        let config = render_template(template, Path::new("floki.yaml"))?;
+        let config = render_template(
+            template,
+            Path::new("floki.yaml"),
+            &TemplateContext::default(),
+        )?;
         assert_eq!(config, "shell: bar");
         Ok(())
     }
@@ -400,11 +1559,58 @@ mod test {
     fn test_tera_tomlload() -> Result<(), Box<dyn std::error::Error>> {
         let template =
             r#"{% set values = toml(file="Cargo.toml") %}floki: {{ values.package.name }}"#;
-        let config = render_template(template, Path::new("floki.yaml"))?;
+        let config = render_template(
+            template,
+            Path::new("floki.yaml"),
+            &TemplateContext::default(),
+        )?;
         assert_eq!(config, "floki: floki");
         Ok(())
     }
 
+    #[test]
+    fn test_tera_load_key() -> Result<(), Box<dyn std::error::Error>> {
+        let template = r#"{{ yaml(file="test_resources/gitlab_reference.yaml", key="build.script.2") }} {{ toml(file="Cargo.toml", key="package.name") }} {{ json(file="test_resources/values.json", key="foo.bar", default="none") }} {{ yaml(file="test_resources/values.yaml", key="missing", default=1) }}"#;
+        let rendered = render_template(
+            template,
+            Path::new("floki.yaml"),
+            &TemplateContext::default(),
+        )?;
+        assert_eq!(rendered, "echo build floki none 1");
+
+        let template = r#"{{ json(file="test_resources/values.json", key="missing") }}"#;
+        let error = render_template(
+            template,
+            Path::new("floki.yaml"),
+            &TemplateContext::default(),
+        )
+        .unwrap_err();
+        assert!(
+            format!("{:?}", error).contains("Failed to find the key 'missing'"),
+            "{:?}",
+            error
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_value_at_key() -> Result<(), Box<dyn std::error::Error>> {
+        let document: YamlValue = serde_yaml::from_str(
+            "jobs:
+  - name: a
+  - name: b
+0: zero
+\"1\": one",
+        )?;
+        let at = |key| value_at_key(&document, key).and_then(YamlValue::as_str);
+        assert_eq!(at("jobs.1.name"), Some("b"));
+        assert_eq!(at("0"), Some("zero"));
+        assert_eq!(at("1"), Some("one"));
+        assert_eq!(at("jobs.2.name"), None);
+        assert_eq!(at("jobs.name"), None);
+        Ok(())
+    }
+
     #[test]
     fn test_strip_yaml_tags_drops_reference_tag() {
         let yaml = "value: !reference [template, script]";
@@ -432,8 +1638,49 @@ mod test {
     #[test]
     fn test_tera_yamlload_with_gitlab_reference() -> Result<(), Box<dyn std::error::Error>> {
         let template = r#"{% set values = yaml(file="test_resources/gitlab_reference.yaml") %}script0: {{ values.job.script[0] }} script1: {{ values.job.script[1] }}"#;
-        let rendered = render_template(template, Path::new("floki.yaml"))?;
+        let rendered = render_template(
+            template,
+            Path::new("floki.yaml"),
+            &TemplateContext::default(),
+        )?;
         assert_eq!(rendered, "script0: .shared_template script1: script");
         Ok(())
     }
+
+    #[test]
+    fn test_tera_yamlload_keeping_tags() -> Result<(), Box<dyn std::error::Error>> {
+        let template = r#"{% set values = yaml(file="test_resources/gitlab_reference.yaml", tags="keep") %}{{ values.job.script.tag }} {{ values.job.script.value | join(sep=".") }}"#;
+        let rendered = render_template(
+            template,
+            Path::new("floki.yaml"),
+            &TemplateContext::default(),
+        )?;
+        assert_eq!(rendered, "!reference .shared_template.script");
+        Ok(())
+    }
+
+    #[test]
+    fn test_tera_yamlload_resolving_references() -> Result<(), Box<dyn std::error::Error>> {
+        let template = r#"{% set values = yaml(file="test_resources/gitlab_reference.yaml", tags="resolve") %}{{ values.job.script | join(sep=";") }}|{{ values.build.script | join(sep=";") }}"#;
+        let rendered = render_template(
+            template,
+            Path::new("floki.yaml"),
+            &TemplateContext::default(),
+        )?;
+        assert_eq!(
+            rendered,
+            "echo template|echo setup;echo template;echo build"
+        );
+
+        let document: YamlValue = serde_yaml::from_str("a: !reference [b]\nb: !reference [a]")?;
+        let error = resolve_yaml_references(&document, &document, 0).unwrap_err();
+        assert!(error.contains("nested more than 10 deep"), "{}", error);
+        let document: YamlValue = serde_yaml::from_str("a: !reference [b, c]\nb: {}")?;
+        let error = resolve_yaml_references(&document, &document, 0).unwrap_err();
+        assert_eq!(error, "!reference [b, c]: c not found");
+        let document: YamlValue = serde_yaml::from_str("a: !reference b")?;
+        let error = resolve_yaml_references(&document, &document, 0).unwrap_err();
+        assert_eq!(error, "!reference must be a list of keys, not b");
+        Ok(())
+    }
 }
@@ -0,0 +1,436 @@
+/// Helpful, source-located diagnostics for configuration files
+use serde::de::{DeserializeOwned, Error as DeError};
+use serde::{Deserialize, Deserializer};
+use serde_yaml::Value as YamlValue;
+use yaml_rust2::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust2::scanner::Marker;
+
+use std::collections::HashMap;
+use std::fmt;
+
+/// A problem found while parsing a configuration file
+#[derive(Debug)]
+pub struct ConfigDiagnostic {
+    /// The name of the file (or files) being parsed
+    pub name: String,
+    /// The path to the offending value, e.g. `mounts[1].target`
+    pub path: Option<String>,
+    /// What went wrong
+    pub message: String,
+    /// The line and column (both starting at 1) in the rendered file
+    pub position: Option<(usize, usize)>,
+    /// The line in the template the rendered line came from, if it
+    /// differs from the rendered line
+    pub template_line: Option<usize>,
+    /// Whether the position refers to a template which was changed by
+    /// rendering, and couldn't be mapped back
+    pub rendered: bool,
+    /// The offending line of the rendered file
+    pub snippet: Option<String>,
+    /// A suggestion for fixing the problem
+    pub help: Option<String>,
+}
+
+impl ConfigDiagnostic {
+    pub(crate) fn new(name: &str, path: Option<String>, message: String) -> Self {
+        let path = path.filter(|p| !p.is_empty() && p != ".");
+        // Some parsers already include the path in their message.
+        let message = match &path {
+            Some(path) => message
+                .strip_prefix(&format!("{path}: "))
+                .map(str::to_string)
+                .unwrap_or(message),
+            None => message,
+        };
+        let (path, message) = match split_nested(path.as_deref().unwrap_or_default(), &message) {
+            Some((nested, message)) => (Some(nested), message),
+            None => (path, message),
+        };
+        let help = suggestion(&message).map(|s| format!("did you mean `{s}`?"));
+        ConfigDiagnostic {
+            name: name.to_string(),
+            path,
+            message,
+            position: None,
+            template_line: None,
+            rendered: false,
+            snippet: None,
+            help,
+        }
+    }
+
+    /// Point the diagnostic at a position in the rendered file, mapping
+    /// it back to the template where possible
+    pub(crate) fn at(mut self, position: (usize, usize), template: &str, rendered: &str) -> Self {
+        let (line, _) = position;
+        self.position = Some(position);
+        self.snippet = rendered.lines().nth(line - 1).map(str::to_string);
+        if template != rendered {
+            match template_line(template, rendered, line) {
+                Some(source_line) if source_line != line => self.template_line = Some(source_line),
+                Some(_) => (),
+                None => self.rendered = true,
+            }
+        }
+        self
+    }
+
+    /// Point the diagnostic into a YAML document, preferring an unknown
+    /// key named in the message, then the position the parser reported,
+    /// then the value at the diagnostic's path
+    pub(crate) fn at_yaml(
+        self,
+        reported: Option<(usize, usize)>,
+        template: &str,
+        rendered: &str,
+    ) -> Self {
+        let positions = yaml_positions(rendered);
+        let path = self.path.clone().unwrap_or_default();
+        let unknown_key = unknown_name(&self.message)
+            .and_then(|(unknown, _)| positions.get(&child_path(&path, unknown)).copied());
+        match unknown_key
+            .or(reported)
+            .or_else(|| positions.get(&path).copied())
+        {
+            Some(position) => self.at(position, template, rendered),
+            None => self,
+        }
+    }
+}
+
+impl fmt::Display for ConfigDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "There was a problem parsing the configuration file '{}': ",
+            self.name
+        )?;
+        if let Some(path) = &self.path {
+            write!(f, "{path}: ")?;
+        }
+        write!(f, "{}", self.message)?;
+
+        if let Some((line, column)) = self.position {
+            write!(f, "\n  --> {}:{line}:{column}", self.name)?;
+            if let Some(template_line) = self.template_line {
+                write!(f, " (line {template_line} of the template)")?;
+            } else if self.rendered {
+                write!(f, " (after rendering the template)")?;
+            }
+            if let Some(snippet) = &self.snippet {
+                let gutter = " ".repeat(line.to_string().len());
+                write!(f, "\n {gutter} |")?;
+                write!(f, "\n {line} | {snippet}")?;
+                write!(f, "\n {gutter} | {}^", " ".repeat(column - 1))?;
+            }
+        }
+
+        if let Some(help) = &self.help {
+            write!(f, "\n  help: {help}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Suggest a replacement for an unknown field or variant, using the
+/// alternatives listed in serde's error message
+fn suggestion(message: &str) -> Option<String> {
+    let (unknown, expected) = unknown_name(message)?;
+    expected
+        .split('`')
+        .skip(1)
+        .step_by(2)
+        .map(|candidate| (strsim::damerau_levenshtein(unknown, candidate), candidate))
+        .filter(|(distance, candidate)| *distance <= std::cmp::max(2, candidate.len() / 3))
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate.to_string())
+}
+
+/// Split a serde message about an unknown field or variant into the
+/// unknown name and the rest of the message, which lists the alternatives
+fn unknown_name(message: &str) -> Option<(&str, &str)> {
+    message
+        .strip_prefix("unknown field `")
+        .or_else(|| message.strip_prefix("unknown variant `"))?
+        .split_once('`')
+}
+
+fn child_path(path: &str, name: &str) -> String {
+    if path.is_empty() || name.starts_with('[') {
+        format!("{path}{name}")
+    } else {
+        format!("{path}.{name}")
+    }
+}
+
+/// Split the path within a variant map, added by `single_key`, from the
+/// message, returning the full path and the rest of the message
+fn split_nested(path: &str, message: &str) -> Option<(String, String)> {
+    let (nested, message) = message.strip_prefix("in `")?.split_once("`: ")?;
+    Some((child_path(path, nested), message.to_string()))
+}
+
+/// Find the line of the template which produced a line of the rendered
+/// file. Templating can add or remove lines, so look for a unique line
+/// with the same text, falling back to the same line number when
+/// rendering didn't change the number of lines.
+fn template_line(template: &str, rendered: &str, line: usize) -> Option<usize> {
+    let text = rendered.lines().nth(line - 1)?;
+    let matches = template
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| *l == text)
+        .map(|(i, _)| i + 1)
+        .collect::<Vec<_>>();
+    match matches.as_slice() {
+        [only] => Some(*only),
+        _ if template.lines().count() == rendered.lines().count() => Some(line),
+        _ => None,
+    }
+}
+
+/// Find the position of every value in a YAML document, keyed by its
+/// path in the same form as `serde_path_to_error`. Map entries are
+/// located at their key, sequence items at their first scalar.
+fn yaml_positions(document: &str) -> HashMap<String, (usize, usize)> {
+    let mut locator = YamlLocator::default();
+    // Documents which fail to parse have already been reported by serde.
+    let _ = Parser::new_from_str(document).load(&mut locator, false);
+    locator.positions
+}
+
+enum YamlNode {
+    Mapping { key: Option<String> },
+    Sequence { index: usize },
+}
+
+#[derive(Default)]
+struct YamlLocator {
+    stack: Vec<(String, YamlNode)>,
+    /// Paths of sequence items which are waiting for their first scalar
+    pending: Vec<String>,
+    positions: HashMap<String, (usize, usize)>,
+}
+
+impl YamlLocator {
+    /// Work out the path of the value starting at this event, updating
+    /// the parent's state
+    fn next_value(&mut self, marker: Marker, scalar: Option<&str>) -> Option<String> {
+        let position = (marker.line(), marker.col() + 1);
+        match self.stack.last_mut() {
+            None => Some(String::new()),
+            Some((path, YamlNode::Mapping { key })) => match key.take() {
+                Some(key) => Some(key),
+                None => {
+                    // This event is a key rather than a value.
+                    let child = child_path(path, scalar.unwrap_or("?"));
+                    self.positions.entry(child.clone()).or_insert(position);
+                    *key = Some(child);
+                    None
+                }
+            },
+            Some((path, YamlNode::Sequence { index })) => {
+                let child = format!("{path}[{index}]");
+                *index += 1;
+                self.pending.push(child.clone());
+                Some(child)
+            }
+        }
+    }
+}
+
+impl MarkedEventReceiver for YamlLocator {
+    fn on_event(&mut self, event: Event, marker: Marker) {
+        let position = (marker.line(), marker.col() + 1);
+        match event {
+            Event::Scalar(value, ..) => {
+                self.next_value(marker, Some(&value));
+                for path in self.pending.drain(..) {
+                    self.positions.entry(path).or_insert(position);
+                }
+            }
+            Event::Alias(_) => {
+                self.next_value(marker, None);
+            }
+            Event::MappingStart(..) => {
+                if let Some(path) = self.next_value(marker, None) {
+                    self.stack.push((path, YamlNode::Mapping { key: None }));
+                }
+            }
+            Event::SequenceStart(..) => {
+                if let Some(path) = self.next_value(marker, None) {
+                    self.stack.push((path, YamlNode::Sequence { index: 0 }));
+                }
+            }
+            Event::MappingEnd | Event::SequenceEnd => {
+                self.stack.pop();
+            }
+            _ => (),
+        }
+    }
+}
+
+/// One of the ways a value of an untagged enum can be written
+pub(crate) struct Variant<T> {
+    /// What the variant looks like, for error messages
+    pub(crate) description: &'static str,
+    /// Keys which identify the variant when it's written as a map. Empty
+    /// for variants which aren't maps.
+    pub(crate) keys: &'static [&'static str],
+    /// Parse the value as this variant
+    pub(crate) parse: fn(YamlValue) -> Result<T, serde_yaml::Error>,
+}
+
+/// Deserialize an untagged enum, explaining what was wrong with the value
+/// rather than reporting that it didn't match any variant. A map is
+/// parsed as the variant its keys identify, so errors come from that
+/// variant alone.
+pub(crate) fn deserialize_untagged<'de, D, T>(
+    deserializer: D,
+    what: &str,
+    variants: &[Variant<T>],
+) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = YamlValue::deserialize(deserializer)?;
+    let expected = variants
+        .iter()
+        .map(|v| v.description)
+        .collect::<Vec<_>>()
+        .join(", or ");
+
+    if let YamlValue::Mapping(map) = &value {
+        let keys = map.keys().filter_map(YamlValue::as_str).collect::<Vec<_>>();
+        if let Some(variant) = variants
+            .iter()
+            .find(|v| v.keys.iter().any(|k| keys.contains(k)))
+        {
+            return (variant.parse)(value).map_err(D::Error::custom);
+        }
+        let known = variants
+            .iter()
+            .flat_map(|v| v.keys.iter())
+            .map(|k| format!("`{k}`"))
+            .collect::<Vec<_>>();
+        if let (Some(unknown), false) = (keys.first(), known.is_empty()) {
+            return Err(D::Error::custom(format!(
+                "unknown field `{unknown}`, expected one of {} ({what} should be {expected})",
+                known.join(", ")
+            )));
+        }
+    } else if let Some(parsed) = variants
+        .iter()
+        .filter(|v| v.keys.is_empty())
+        .find_map(|v| (v.parse)(value.clone()).ok())
+    {
+        return Ok(parsed);
+    }
+
+    Err(D::Error::custom(format!(
+        "invalid {what}: expected {expected}, found {}",
+        describe(&value)
+    )))
+}
+
+/// Parse the value of a key identifying a variant written as a map. Any
+/// other keys are ignored, as they were when these enums were untagged.
+pub(crate) fn single_key<T: DeserializeOwned>(
+    value: YamlValue,
+    key: &str,
+) -> Result<T, serde_yaml::Error> {
+    let mut map = match value {
+        YamlValue::Mapping(map) => map,
+        other => {
+            return Err(serde_yaml::Error::custom(format!(
+                "expected a map, found {}",
+                describe(&other)
+            )))
+        }
+    };
+    let inner = map.remove(key).unwrap_or(YamlValue::Null);
+    // The path isn't known here, so keep the path within the variant in
+    // the message for `ConfigDiagnostic` to point at.
+    serde_path_to_error::deserialize(inner).map_err(|e| {
+        let path = match e.path().to_string() {
+            inner if inner == "." => key.to_string(),
+            inner => child_path(key, &inner),
+        };
+        let message = e.into_inner().to_string();
+        let (path, message) = split_nested(&path, &message).unwrap_or((path, message));
+        serde_yaml::Error::custom(format!("in `{path}`: {message}"))
+    })
+}
+
+/// Report the first key of a map which isn't one of the known keys
+pub(crate) fn unknown_key(value: &YamlValue, known: &[&str]) -> Option<serde_yaml::Error> {
+    let unknown = value
+        .as_mapping()?
+        .keys()
+        .find(|k| !k.as_str().is_some_and(|k| known.contains(&k)))?;
+    let known = known
+        .iter()
+        .map(|k| format!("`{k}`"))
+        .collect::<Vec<_>>()
+        .join(" or ");
+    Some(serde_yaml::Error::custom(format!(
+        "unknown field `{}`, expected {known}",
+        unknown.as_str().unwrap_or("?")
+    )))
+}
+
+fn describe(value: &YamlValue) -> &'static str {
+    match value {
+        YamlValue::Null => "nothing",
+        YamlValue::Bool(_) => "a boolean",
+        YamlValue::Number(_) => "a number",
+        YamlValue::String(_) => "a string",
+        YamlValue::Sequence(_) => "a list",
+        YamlValue::Mapping(_) => "a map",
+        YamlValue::Tagged(_) => "a tagged value",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_suggestion() {
+        assert_eq!(
+            suggestion("unknown field `imgae`, expected one of `image`, `init`, `shell`"),
+            Some("image".into())
+        );
+        assert_eq!(
+            suggestion("unknown variant `tmfps`, expected one of `bind`, `tmpfs`, `volume`"),
+            Some("tmpfs".into())
+        );
+        assert_eq!(
+            suggestion("unknown field `zzz`, expected `image` or `init`"),
+            None
+        );
+        assert_eq!(suggestion("missing field `image`"), None);
+    }
+
+    #[test]
+    fn test_yaml_positions() {
+        let positions = yaml_positions(
+            "image: debian\nmounts:\n  - target: /a\n  - source: b\n    target: /b\n",
+        );
+        assert_eq!(positions["image"], (1, 1));
+        assert_eq!(positions["mounts"], (2, 1));
+        assert_eq!(positions["mounts[1]"], (4, 5));
+        assert_eq!(positions["mounts[1].target"], (5, 5));
+    }
+
+    #[test]
+    fn test_template_line() {
+        let template = "{% if true %}\nimage: debian\n{% endif %}\nshell: bash\n";
+        let rendered = "\nimage: debian\n\nshell: bash\n";
+        assert_eq!(template_line(template, rendered, 4), Some(4));
+        let template = "{% for i in [1, 2] %}\n- x{{ i }}\n{% endfor %}\nshell: bash\n";
+        let rendered = "\n- x1\n\n- x2\n\nshell: bash\n";
+        assert_eq!(template_line(template, rendered, 6), Some(4));
+        assert_eq!(template_line(template, rendered, 4), None);
+    }
+}
@@ -1,5 +1,10 @@
 /// Query the current user environment
+use crate::cli::Cli;
+use crate::config::{ConfigFormat, FlokiVariables, HostUser, TemplateContext};
 use crate::errors::FlokiError;
+use crate::merge::find_override_files;
+use crate::policy::{find_policy_files, SYSTEM_POLICY_FILE};
+use crate::vars::template_vars;
 use anyhow::Error;
 use std::env;
 use std::ffi::OsString;
@@ -15,7 +20,7 @@ pub struct User {
 
 impl User {
     /// Get the user and group ids of the current user
-    fn current() -> Self {
+    pub(crate) fn current() -> Self {
         let uid = nix::unistd::getuid();
         let gid = nix::unistd::getgid();
         debug!("Current user has uid {uid} and group {gid}");
@@ -23,37 +28,129 @@ impl User {
     }
 }
 
+/// How the container should be attached to the terminal
+#[derive(Debug, Clone, Copy)]
+pub struct Terminal {
+    /// Allocate a TTY for the container
+    pub tty: bool,
+    /// Keep the container's stdin attached
+    pub interactive: bool,
+}
+
+impl Terminal {
+    /// Decide how to attach the container, honouring any explicit choices.
+    /// A TTY merges stderr into stdout and mangles binary output, so is
+    /// only allocated by default when both stdin and stdout are terminals.
+    fn detect(tty: Option<bool>, interactive: Option<bool>) -> Self {
+        let terminal = Terminal {
+            tty: tty
+                .unwrap_or_else(|| atty::is(atty::Stream::Stdin) && atty::is(atty::Stream::Stdout)),
+            interactive: interactive.unwrap_or(true),
+        };
+        debug!("Attaching container with {terminal:?}");
+        terminal
+    }
+}
+
+/// The rules for deciding the floki root, in order of precedence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RootSource {
+    /// The `--root` command line option
+    CommandLine,
+    /// The `root` key in the configuration file
+    ConfigKey,
+    /// The directory containing the configuration file
+    ConfigLocation,
+}
+
+impl std::fmt::Display for RootSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RootSource::CommandLine => write!(f, "set by --root"),
+            RootSource::ConfigKey => write!(f, "set by `root` in the configuration file"),
+            RootSource::ConfigLocation => write!(f, "directory containing the configuration file"),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Environment {
     /// User uid and gid
     pub user_details: User,
     /// The directory floki was launched in
     pub current_directory: path::PathBuf,
-    /// The root directory for floki (may be different from
-    /// the above if we had to search for floki.yaml
+    /// The root directory for floki, which is mounted into the container
     pub floki_root: path::PathBuf,
+    /// Which rule decided the floki root
+    pub root_source: RootSource,
     /// Absolute path to the configuration file
     pub config_file: path::PathBuf,
+    /// The format of the configuration file
+    pub config_format: ConfigFormat,
+    /// Local override files found next to the configuration file, in the
+    /// order they are applied
+    pub override_files: Vec<path::PathBuf>,
+    /// Path to the user's configuration file, if there is one to apply
+    pub user_config_file: Option<path::PathBuf>,
+    /// The policy files which restrict what the configuration may do
+    pub policy_files: Vec<path::PathBuf>,
     /// Path to ssh socket if found
     pub ssh_agent_socket: Option<OsString>,
     /// The host folder that floki uses to e.g. create directories
     /// to back volumes
     pub floki_workspace: path::PathBuf,
+    /// How the container should be attached to the terminal
+    pub terminal: Terminal,
+    /// Variables for templates from `--values` and `--set`
+    pub template_vars: serde_json::Map<String, serde_json::Value>,
+    /// Shared directories to find included templates in
+    pub template_path: Vec<path::PathBuf>,
+    /// Directories outside the project which templates may read
+    pub allowed_template_paths: Vec<path::PathBuf>,
 }
 
 impl Environment {
     /// Gather information on the environment floki is running in
-    pub fn gather(config_file: &Option<path::PathBuf>) -> Result<Self, Error> {
-        let (floki_root, config_path) = resolve_floki_root_and_config(config_file)?;
+    pub fn gather(args: &Cli) -> Result<Self, Error> {
+        let config_file = normalize_path(resolve_config_file(&args.config_file)?)?;
+        let (floki_root, root_source) = match &args.root {
+            Some(root) => (root.clone(), RootSource::CommandLine),
+            None => (config_directory(&config_file)?, RootSource::ConfigLocation),
+        };
         let user = User::current();
 
+        // Canonicalise the directories so that symlinks don't stop us
+        // relating the current directory to the floki root.
         let env = Environment {
             user_details: user,
-            current_directory: get_current_working_directory()?,
-            floki_root,
-            config_file: normalize_path(config_path)?,
+            current_directory: normalize_path(get_current_working_directory()?)?,
+            floki_root: normalize_path(floki_root)?,
+            root_source,
+            config_format: args
+                .config_format
+                .unwrap_or_else(|| ConfigFormat::from_path(&config_file)),
+            override_files: find_override_files(&config_directory(&config_file)?),
+            config_file,
+            user_config_file: if args.no_user_config {
+                None
+            } else {
+                find_user_config(env::var_os("XDG_CONFIG_HOME"), env::var_os("HOME"))
+            },
+            policy_files: find_policy_files(
+                path::Path::new(SYSTEM_POLICY_FILE),
+                user_config_directory(env::var_os("XDG_CONFIG_HOME"), env::var_os("HOME"))
+                    .as_deref(),
+            ),
             ssh_agent_socket: get_ssh_agent_socket_path(),
             floki_workspace: get_floki_work_path(user.uid),
+            terminal: Terminal::detect(args.tty(), args.interactive()),
+            template_vars: template_vars(&args.values, &args.set)?,
+            template_path: args
+                .template_path
+                .as_ref()
+                .map(|p| env::split_paths(p).collect())
+                .unwrap_or_default(),
+            allowed_template_paths: args.allow_template_path.clone(),
         };
 
         debug!("Got environment {:?}", &env);
@@ -62,6 +159,70 @@ impl Environment {
     }
 }
 
+impl Environment {
+    /// The context templates in the configuration are rendered with
+    pub fn template_context(&self) -> TemplateContext {
+        let uid = self.user_details.uid;
+        let name = nix::unistd::User::from_uid(uid)
+            .ok()
+            .flatten()
+            .map(|user| user.name)
+            .or_else(|| env::var("USER").ok())
+            .unwrap_or_default();
+        TemplateContext {
+            floki: FlokiVariables {
+                root: self.floki_root.clone(),
+                config: self.config_file.clone(),
+                current_directory: self.current_directory.clone(),
+                workspace: self.floki_workspace.clone(),
+                user: HostUser {
+                    uid: uid.as_raw(),
+                    gid: self.user_details.gid.as_raw(),
+                    name,
+                },
+                arch: env::consts::ARCH.into(),
+                docker_arch: docker_arch(env::consts::ARCH, cfg!(target_endian = "little")).into(),
+                os: env::consts::OS.into(),
+                version: env!("CARGO_PKG_VERSION").into(),
+            },
+            vars: self.template_vars.clone(),
+            template_path: self.template_path.clone(),
+            allowed_paths: self.allowed_template_paths.clone(),
+        }
+    }
+
+    /// Apply the `root` key from the configuration file, which is relative
+    /// to the configuration file and is overridden by `--root`.
+    pub fn apply_config_root(&mut self, root: Option<&path::Path>) -> Result<(), Error> {
+        if let (Some(root), RootSource::ConfigLocation) = (root, self.root_source) {
+            self.floki_root = normalize_path(config_directory(&self.config_file)?.join(root))?;
+            self.root_source = RootSource::ConfigKey;
+            debug!("Using floki root {:?} from configuration", self.floki_root);
+        }
+        Ok(())
+    }
+}
+
+/// Translate a Rust architecture name into the name docker uses for
+/// image platforms, which also depends on the byte order for some
+fn docker_arch(arch: &str, little_endian: bool) -> &str {
+    match arch {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "x86" => "386",
+        "arm" => "arm",
+        "powerpc64" if little_endian => "ppc64le",
+        "powerpc64" => "ppc64",
+        other => other,
+    }
+}
+
+/// Resolve the floki workspace for the current user, for commands which
+/// don't need a configuration file
+pub fn floki_workspace() -> path::PathBuf {
+    get_floki_work_path(User::current().uid)
+}
+
 /// Get the current working directory as a String
 fn get_current_working_directory() -> Result<path::PathBuf, Error> {
     Ok(env::current_dir()?)
@@ -73,37 +234,85 @@ fn get_ssh_agent_socket_path() -> Option<OsString> {
     env::var_os("SSH_AUTH_SOCK")
 }
 
-/// Search all ancestors of the current directory for a floki.yaml file name.
-fn find_floki_yaml(current_directory: &path::Path) -> Result<path::PathBuf, Error> {
-    current_directory
-        .ancestors()
-        .map(|a| a.join("floki.yaml"))
-        .find(|f| f.is_file())
-        .ok_or_else(|| FlokiError::ProblemFindingConfigYaml {}.into())
+/// The directory holding the user's floki configuration, following the
+/// XDG base directory specification
+fn user_config_directory(
+    xdg_config_home: Option<OsString>,
+    home: Option<OsString>,
+) -> Option<path::PathBuf> {
+    let config_home = xdg_config_home
+        .filter(|dir| !dir.is_empty())
+        .map(path::PathBuf::from)
+        .or_else(|| home.map(|home| path::Path::new(&home).join(".config")))?;
+    Some(config_home.join("floki"))
 }
 
-/// Take a file path, and return a tuple consisting of its parent directory and the file path
-fn locate_file_in_parents(path: path::PathBuf) -> Result<(path::PathBuf, path::PathBuf), Error> {
-    let dir = path
+/// Locate the user's configuration file. Returns None if the file doesn't
+/// exist.
+fn find_user_config(
+    xdg_config_home: Option<OsString>,
+    home: Option<OsString>,
+) -> Option<path::PathBuf> {
+    let user_config = user_config_directory(xdg_config_home, home)?.join("config.yaml");
+    if user_config.is_file() {
+        Some(user_config)
+    } else {
+        None
+    }
+}
+
+/// The configuration file names floki searches for, in order of preference
+pub const CONFIG_FILE_NAMES: &[&str] = &[
+    "floki.yaml",
+    ".floki.yaml",
+    "floki.yml",
+    "floki.toml",
+    "floki.json",
+];
+
+/// Search the current directory and its ancestors for a configuration file.
+/// The search stops at the root of the enclosing git repository, or at the
+/// home directory, whichever comes first.
+fn find_floki_yaml(
+    current_directory: &path::Path,
+    home: Option<&path::Path>,
+) -> Result<path::PathBuf, Error> {
+    let mut searched = Vec::new();
+    for directory in current_directory.ancestors() {
+        if let Some(found) = CONFIG_FILE_NAMES
+            .iter()
+            .map(|name| directory.join(name))
+            .find(|f| f.is_file())
+        {
+            return Ok(found);
+        }
+        searched.push(directory.to_path_buf());
+        if directory.join(".git").exists() || Some(directory) == home {
+            break;
+        }
+    }
+    Err(FlokiError::ProblemFindingConfigYaml { searched }.into())
+}
+
+/// Get the directory containing a configuration file
+fn config_directory(path: &path::Path) -> Result<path::PathBuf, Error> {
+    Ok(path
         .parent()
         .ok_or_else(|| FlokiError::InternalAssertionFailed {
             description: format!("config_file '{:?}' does not have a parent", &path),
         })?
-        .to_path_buf();
-    Ok((dir, path))
+        .to_path_buf())
 }
 
-/// Resolve floki root directory and path to configuration file. The floki root directory
-/// here is the folder in which the floki.yaml was found when no configuration file
-/// is specified, and we have to search for it.
-fn resolve_floki_root_and_config(
-    config_file: &Option<path::PathBuf>,
-) -> Result<(path::PathBuf, path::PathBuf), Error> {
+/// Resolve the path to the configuration file, searching for it if one
+/// was not specified
+fn resolve_config_file(config_file: &Option<path::PathBuf>) -> Result<path::PathBuf, Error> {
     match config_file {
-        Some(path) => Ok((get_current_working_directory()?, path.clone())),
-        None => Ok(locate_file_in_parents(find_floki_yaml(
+        Some(path) => Ok(path.clone()),
+        None => find_floki_yaml(
             &get_current_working_directory()?,
-        )?)?),
+            env::var_os("HOME").as_ref().map(path::Path::new),
+        ),
     }
 }
 
@@ -143,12 +352,89 @@ mod test {
         Ok(())
     }
 
+    fn test_environment(root: &path::Path, root_source: RootSource) -> Environment {
+        Environment {
+            user_details: User::current(),
+            current_directory: root.to_path_buf(),
+            floki_root: root.to_path_buf(),
+            root_source,
+            config_file: root.join("config/floki.yaml"),
+            config_format: ConfigFormat::Yaml,
+            override_files: Vec::new(),
+            user_config_file: None,
+            policy_files: Vec::new(),
+            ssh_agent_socket: None,
+            floki_workspace: root.join(".floki"),
+            terminal: Terminal {
+                tty: false,
+                interactive: false,
+            },
+            template_vars: serde_json::Map::new(),
+            template_path: Vec::new(),
+            allowed_template_paths: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_docker_arch() {
+        assert_eq!(docker_arch("x86_64", true), "amd64");
+        assert_eq!(docker_arch("powerpc64", true), "ppc64le");
+        assert_eq!(docker_arch("powerpc64", false), "ppc64");
+        assert_eq!(docker_arch("s390x", false), "s390x");
+    }
+
+    #[test]
+    fn test_apply_config_root_relative_to_config() -> Result<(), Error> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let root = normalize_path(tmp_dir.path().to_path_buf())?;
+        fs::create_dir_all(root.join("config"))?;
+
+        let mut env = test_environment(&root.join("config"), RootSource::ConfigLocation);
+        env.config_file = root.join("config/floki.yaml");
+        env.apply_config_root(Some(path::Path::new("..")))?;
+
+        assert_eq!(env.floki_root, root);
+        assert_eq!(env.root_source, RootSource::ConfigKey);
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_config_root_overridden_by_command_line() -> Result<(), Error> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let mut env = test_environment(tmp_dir.path(), RootSource::CommandLine);
+        env.apply_config_root(Some(path::Path::new("/")))?;
+
+        assert_eq!(env.floki_root, tmp_dir.path());
+        assert_eq!(env.root_source, RootSource::CommandLine);
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_user_config() -> Result<(), Error> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let home = tmp_dir.path().join("home");
+        let xdg = tmp_dir.path().join("xdg");
+        fs::create_dir_all(home.join(".config/floki"))?;
+        fs::write(home.join(".config/floki/config.yaml"), "")?;
+
+        assert_eq!(
+            find_user_config(None, Some(home.clone().into())),
+            Some(home.join(".config/floki/config.yaml"))
+        );
+        assert_eq!(
+            find_user_config(Some("".into()), Some(home.clone().into())),
+            Some(home.join(".config/floki/config.yaml"))
+        );
+        assert_eq!(find_user_config(Some(xdg.into()), Some(home.into())), None);
+        Ok(())
+    }
+
     #[test]
     fn test_find_floki_yaml_current_dir() -> Result<(), Error> {
         let tmp_dir = tempfile::TempDir::new()?;
         let floki_yaml_path = tmp_dir.path().join("floki.yaml");
         touch_file(&floki_yaml_path)?;
-        assert_eq!(find_floki_yaml(tmp_dir.path())?, floki_yaml_path);
+        assert_eq!(find_floki_yaml(tmp_dir.path(), None)?, floki_yaml_path);
         Ok(())
     }
 
@@ -158,7 +444,7 @@ mod test {
         let floki_yaml_path = tmp_dir.path().join("floki.yaml");
         touch_file(&floki_yaml_path)?;
         assert_eq!(
-            find_floki_yaml(&tmp_dir.path().join("dir/subdir"))?,
+            find_floki_yaml(&tmp_dir.path().join("dir/subdir"), None)?,
             floki_yaml_path
         );
         Ok(())
@@ -169,7 +455,42 @@ mod test {
         let tmp_dir = tempfile::TempDir::new()?;
         let floki_yaml_path = tmp_dir.path().join("src/floki.yaml");
         touch_file(&floki_yaml_path)?;
-        assert!(find_floki_yaml(&tmp_dir.path().join("include")).is_err());
+        assert!(find_floki_yaml(&tmp_dir.path().join("include"), None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_floki_yaml_alternative_names() -> Result<(), Error> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let dotfile_path = tmp_dir.path().join(".floki.yaml");
+        touch_file(&dotfile_path)?;
+        touch_file(&tmp_dir.path().join("floki.toml"))?;
+        assert_eq!(find_floki_yaml(tmp_dir.path(), None)?, dotfile_path);
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_floki_yaml_stops_at_git_root() -> Result<(), Error> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        touch_file(&tmp_dir.path().join("floki.yaml"))?;
+        fs::create_dir_all(tmp_dir.path().join("repo/.git"))?;
+        let err = find_floki_yaml(&tmp_dir.path().join("repo/src"), None).unwrap_err();
+        match err.downcast_ref::<FlokiError>() {
+            Some(FlokiError::ProblemFindingConfigYaml { searched }) => assert_eq!(
+                searched,
+                &vec![tmp_dir.path().join("repo/src"), tmp_dir.path().join("repo")]
+            ),
+            other => panic!("unexpected error {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_floki_yaml_stops_at_home() -> Result<(), Error> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        touch_file(&tmp_dir.path().join("floki.yaml"))?;
+        let home = tmp_dir.path().join("home");
+        assert!(find_floki_yaml(&home.join("project"), Some(&home)).is_err());
         Ok(())
     }
 }
@@ -1,6 +1,7 @@
 /// Error type for floki
 use std::fmt;
 use std::io;
+use std::path::PathBuf;
 use std::process::ExitStatus;
 
 /// FlokiSubprocessExitStatus is a structure which wraps an exit status
@@ -13,8 +14,12 @@ pub struct FlokiSubprocessExitStatus {
 /// Error types for Floki
 #[derive(Debug, thiserror::Error)]
 pub enum FlokiError {
-    #[error("No floki.yaml found in tree")]
-    ProblemFindingConfigYaml {},
+    #[error(
+        "No configuration file ({}) found in any of:\n{}",
+        crate::environment::CONFIG_FILE_NAMES.join(", "),
+        display_paths(.searched)
+    )]
+    ProblemFindingConfigYaml { searched: Vec<PathBuf> },
 
     #[error("Could not normalize the file path '{name}': {error:?}")]
     ProblemNormalizingFilePath { name: String, error: io::Error },
@@ -22,13 +27,77 @@ pub enum FlokiError {
     #[error("There was a problem rendering the template '{name}': {error:?}")]
     ProblemRenderingTemplate { name: String, error: tera::Error },
 
+    #[error("The template '{name}' used by '{parent}' was not found in any of:\n{}", display_paths(.searched))]
+    TemplateNotFound {
+        name: String,
+        parent: String,
+        searched: Vec<PathBuf>,
+    },
+
+    #[error(
+        "Templates may not read '{}', which is outside the directories they are allowed to read:\n{}\nUse --allow-template-path to allow it",
+        .path.display(),
+        display_paths(.allowed)
+    )]
+    TemplateAccessDenied {
+        path: PathBuf,
+        allowed: Vec<PathBuf>,
+    },
+
+    #[error("Templates include each other in a cycle: {}", .cycle.join(" -> "))]
+    TemplateIncludeCycle { cycle: Vec<String> },
+
     #[error("There was a problem opening the configuration file '{name}': {error:?}")]
     ProblemOpeningConfigYaml { name: String, error: std::io::Error },
 
-    #[error("There was a problem parsing the configuration file '{name}': {error:?}")]
-    ProblemParsingConfigYaml {
+    #[error("There was a problem opening the values file '{name}': {error:?}")]
+    ProblemOpeningValuesFile { name: String, error: std::io::Error },
+
+    #[error("The values file '{name}' must contain a map of variables")]
+    ValuesFileNotAMap { name: String },
+
+    #[error("{diagnostic}")]
+    ProblemParsingConfig {
+        diagnostic: Box<crate::diagnostics::ConfigDiagnostic>,
+    },
+
+    #[error("There was a problem parsing the configuration merged from {names}: {error}")]
+    ProblemParsingMergedConfig {
+        names: String,
+        error: serde_path_to_error::Error<serde_json::Error>,
+    },
+
+    #[error("Found {count} problem(s) in the configuration file '{name}'")]
+    InvalidConfig { name: String, count: usize },
+
+    #[error(
+        "The configuration file '{name}' {}, and would:\n{}\nRun `floki trust` once you have reviewed it",
+        if *.changed { "has changed since it was trusted" } else { "hasn't been trusted" },
+        .risks.iter().map(|r| format!("  - {r}")).collect::<Vec<_>>().join("\n")
+    )]
+    UntrustedConfig {
         name: String,
-        error: serde_yaml::Error,
+        changed: bool,
+        risks: Vec<String>,
+    },
+
+    #[error(
+        "The policy in '{}' forbids this configuration: {reason} (rule {rule})",
+        .policy.display()
+    )]
+    PolicyViolation {
+        policy: PathBuf,
+        rule: String,
+        reason: String,
+    },
+
+    #[error("The policy in '{}' is invalid: {reason}", .policy.display())]
+    InvalidPolicy { policy: PathBuf, reason: String },
+
+    #[error("Unable to convert the configuration to {format}: {error}")]
+    ProblemConvertingConfig {
+        format: crate::config::ConfigFormat,
+        error: String,
     },
 
     #[error("Running docker command failed with error: {error:?}")]
@@ -55,6 +124,17 @@ pub enum FlokiError {
     #[error("Failed to find the key '{key}' in file '{file}'")]
     FailedToFindYamlKey { key: String, file: String },
 
+    #[error("Failed to manage docker volume: {exit_status}")]
+    FailedToManageVolume {
+        exit_status: FlokiSubprocessExitStatus,
+    },
+
+    #[error("Failed to seed volume '{name}': {reason}")]
+    FailedToSeedVolume { name: String, reason: String },
+
+    #[error("No volume named '{name}' in the configuration file")]
+    UnknownVolume { name: String },
+
     #[error("Running container failed: {exit_status:?}")]
     RunContainerFailed {
         exit_status: FlokiSubprocessExitStatus,
@@ -66,6 +146,15 @@ pub enum FlokiError {
     #[error("Malformed item in docker_switches: {item}")]
     MalformedDockerSwitch { item: String },
 
+    #[error("Invalid working directory '{path}': {reason}")]
+    InvalidWorkingDirectory { path: String, reason: String },
+
+    #[error("Invalid mount for target '{target}': {reason}")]
+    InvalidMount { target: String, reason: String },
+
+    #[error("Source '{path}' for mount '{target}' does not exist - mark the mount as optional to skip it when missing")]
+    MountSourceNotFound { path: String, target: String },
+
     /// Internal error for floki - these represent failed assumptions of
     /// the developers, and shouldn't actually manifest.
     #[error("An internal assertion failed '{description}'.  This is probably a bug!")]
@@ -75,6 +164,15 @@ pub enum FlokiError {
     InvalidVerbositySetting { setting: u8 },
 }
 
+/// List paths one per line
+fn display_paths(paths: &[PathBuf]) -> String {
+    paths
+        .iter()
+        .map(|p| format!("  {}", p.display()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
 /// Generate a summary string for a process exiting
 fn exit_code_diagnosis(exit_status: &ExitStatus) -> String {
     match exit_status.code() {
@@ -0,0 +1,199 @@
+/// Template functions which inspect files relative to the configuration
+/// file, and hash content
+use crate::config::path_from_args;
+use crate::sandbox::Sandbox;
+
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// The file functions available to templates
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum FileFunction {
+    /// Whether a file or directory exists, `file_exists(file=...)`
+    Exists,
+    /// The content of a file as a string, `read_file(file=...)`
+    Read,
+    /// The files matching a pattern, sorted, `glob(pattern=...)`
+    Glob,
+    /// The SHA-256 digest of a file's content, `sha256_file(file=...)`
+    Sha256File,
+}
+
+impl FileFunction {
+    /// The name of the template function and the function it provides
+    pub(crate) const ALL: &'static [(&'static str, FileFunction)] = &[
+        ("file_exists", FileFunction::Exists),
+        ("read_file", FileFunction::Read),
+        ("glob", FileFunction::Glob),
+        ("sha256_file", FileFunction::Sha256File),
+    ];
+}
+
+/// Make a template function which resolves paths relative to the given
+/// directory, and only reads files the sandbox allows
+pub(crate) fn file_function(
+    directory: PathBuf,
+    function: FileFunction,
+    sandbox: Sandbox,
+) -> impl tera::Function {
+    Box::new(move |args: &HashMap<String, tera::Value>| {
+        let resolve = |args: &HashMap<String, tera::Value>| {
+            let path = directory.join(path_from_args(args)?);
+            sandbox.guard(&path).map(|_| path)
+        };
+        let read = |path: &Path| {
+            std::fs::read(path)
+                .map_err(|e| tera::Error::msg(format!("Failed to read '{}': {e}", path.display())))
+        };
+        match function {
+            FileFunction::Exists => {
+                let path = resolve(args)?;
+                Ok(tera::Value::Bool(path.exists()))
+            }
+            FileFunction::Read => {
+                let path = resolve(args)?;
+                let content = String::from_utf8(read(&path)?).map_err(|_| {
+                    tera::Error::msg(format!("'{}' is not valid UTF-8", path.display()))
+                })?;
+                Ok(tera::Value::String(content))
+            }
+            FileFunction::Glob => {
+                let pattern = string_arg(args, "pattern")?;
+                glob_files(&directory, &pattern, &sandbox).map(|paths| paths.into())
+            }
+            FileFunction::Sha256File => {
+                let path = resolve(args)?;
+                Ok(tera::Value::String(sha256_hex(&read(&path)?)))
+            }
+        }
+    })
+}
+
+/// The `sha256(value=...)` template function. Strings are hashed as they
+/// are, other values are hashed as JSON.
+pub(crate) fn sha256(args: &HashMap<String, tera::Value>) -> tera::Result<tera::Value> {
+    let value = match args.get("value") {
+        Some(tera::Value::String(value)) => value.clone(),
+        Some(other) => other.to_string(),
+        None => return Err("value parameter is required".into()),
+    };
+    Ok(tera::Value::String(sha256_hex(value.as_bytes())))
+}
+
+fn string_arg(args: &HashMap<String, tera::Value>, name: &str) -> tera::Result<String> {
+    match args.get(name) {
+        Some(tera::Value::String(value)) => Ok(value.clone()),
+        Some(other) => Err(format!("{name} parameter must be a string, not {other}").into()),
+        None => Err(format!("{name} parameter is required").into()),
+    }
+}
+
+/// Find the files matching a pattern relative to a directory, returning
+/// them relative to the directory in sorted order. Matches the sandbox
+/// doesn't allow, such as symlinks out of the project, are left out.
+fn glob_files(directory: &Path, pattern: &str, sandbox: &Sandbox) -> tera::Result<Vec<String>> {
+    // The directory may itself contain glob characters, which must match
+    // literally, so only the pattern is searched for them.
+    let full_pattern =
+        Path::new(&glob::Pattern::escape(&directory.to_string_lossy())).join(pattern);
+    let literal_prefix = directory.join(
+        Path::new(pattern)
+            .components()
+            .take_while(|c| !c.as_os_str().to_string_lossy().contains(GLOB_CHARACTERS))
+            .collect::<PathBuf>(),
+    );
+    sandbox.guard(&literal_prefix)?;
+    let entries = glob::glob(&full_pattern.to_string_lossy())
+        .map_err(|e| tera::Error::msg(format!("Invalid glob pattern '{pattern}': {e}")))?;
+    let mut paths = Vec::new();
+    for entry in entries {
+        let path = entry.map_err(|e| tera::Error::msg(e.to_string()))?;
+        if !sandbox.allows(&path) {
+            continue;
+        }
+        let relative = path.strip_prefix(directory).unwrap_or(&path);
+        paths.push(relative.display().to_string());
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// The characters which make a path component a glob pattern
+const GLOB_CHARACTERS: &[char] = &['*', '?', '['];
+
+pub(crate) fn sha256_hex(content: &[u8]) -> String {
+    Sha256::digest(content)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tera::{Context, Tera};
+
+    fn render(directory: &Path, template: &str) -> tera::Result<String> {
+        let mut tera = Tera::default();
+        for (name, function) in FileFunction::ALL {
+            tera.register_function(
+                name,
+                file_function(
+                    directory.to_path_buf(),
+                    *function,
+                    Sandbox::new(vec![directory.to_path_buf()]),
+                ),
+            );
+        }
+        tera.register_function("sha256", sha256);
+        tera.render_str(template, &Context::new())
+    }
+
+    #[test]
+    fn test_file_functions() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        std::fs::create_dir_all(tmp_dir.path().join("locks"))?;
+        std::fs::write(tmp_dir.path().join("locks/b.lock"), "b")?;
+        std::fs::write(tmp_dir.path().join("locks/a.lock"), "a")?;
+
+        let rendered = render(
+            tmp_dir.path(),
+            r#"{{ file_exists(file="locks/a.lock") }} {{ file_exists(file="missing") }} {{ read_file(file="locks/b.lock") }} {{ glob(pattern="locks/*.lock") | join(sep=",") }}"#,
+        )?;
+        assert_eq!(rendered, "true false b locks/a.lock,locks/b.lock");
+        Ok(())
+    }
+
+    #[test]
+    fn test_glob_in_directory_with_glob_characters() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let project = tmp_dir.path().join("project[1]*");
+        std::fs::create_dir_all(&project)?;
+        std::fs::create_dir_all(tmp_dir.path().join("project1x"))?;
+        std::fs::write(project.join("a.lock"), "a")?;
+        std::fs::write(tmp_dir.path().join("project1x/b.lock"), "b")?;
+
+        let rendered = render(&project, r#"{{ glob(pattern="*.lock") | join(sep=",") }}"#)?;
+        assert_eq!(rendered, "a.lock");
+        Ok(())
+    }
+
+    #[test]
+    fn test_hash_functions() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        std::fs::write(tmp_dir.path().join("Cargo.lock"), "abc")?;
+
+        let expected = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert_eq!(
+            render(tmp_dir.path(), r#"{{ sha256_file(file="Cargo.lock") }}"#)?,
+            expected
+        );
+        assert_eq!(
+            render(tmp_dir.path(), r#"{{ sha256(value="abc") }}"#)?,
+            expected
+        );
+        assert!(render(tmp_dir.path(), r#"{{ sha256_file(file="missing") }}"#).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,172 @@
+/// Template functions which query the git repository containing the
+/// configuration file
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// The questions templates can ask about the repository
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum GitQuery {
+    /// The commit checked out, `git_commit(short=false)`
+    Commit,
+    /// The current branch, or `HEAD` if detached, `git_branch()`
+    Branch,
+    /// The closest tag and commits since, `git_describe(dirty=false)`
+    Describe,
+    /// The top level directory of the repository, `git_root()`
+    Root,
+    /// Whether there are uncommitted changes, `git_is_dirty()`
+    IsDirty,
+}
+
+impl GitQuery {
+    /// The name of the template function and the query it answers
+    pub(crate) const ALL: &'static [(&'static str, GitQuery)] = &[
+        ("git_commit", GitQuery::Commit),
+        ("git_branch", GitQuery::Branch),
+        ("git_describe", GitQuery::Describe),
+        ("git_root", GitQuery::Root),
+        ("git_is_dirty", GitQuery::IsDirty),
+    ];
+}
+
+/// Make a template function answering a query about the repository
+/// containing the given directory
+pub(crate) fn git_function(directory: PathBuf, query: GitQuery) -> impl tera::Function {
+    Box::new(move |args: &HashMap<String, tera::Value>| {
+        match query {
+            GitQuery::Commit => {
+                let mut git_args = vec!["rev-parse"];
+                if flag(args, "short")? {
+                    git_args.push("--short");
+                }
+                git_args.push("HEAD");
+                git(&directory, &git_args).map(tera::Value::String)
+            }
+            // Gives `HEAD` when detached, but still fails outside a repository.
+            GitQuery::Branch => {
+                git(&directory, &["rev-parse", "--abbrev-ref", "HEAD"]).map(tera::Value::String)
+            }
+            GitQuery::Describe => {
+                let mut git_args = vec!["describe", "--tags", "--always"];
+                if flag(args, "dirty")? {
+                    git_args.push("--dirty");
+                }
+                git(&directory, &git_args).map(tera::Value::String)
+            }
+            GitQuery::Root => {
+                git(&directory, &["rev-parse", "--show-toplevel"]).map(tera::Value::String)
+            }
+            GitQuery::IsDirty => git(&directory, &["status", "--porcelain"])
+                .map(|status| tera::Value::Bool(!status.is_empty())),
+        }
+    })
+}
+
+/// Read an optional boolean argument, which defaults to false
+fn flag(args: &HashMap<String, tera::Value>, name: &str) -> tera::Result<bool> {
+    match args.get(name) {
+        None => Ok(false),
+        Some(tera::Value::Bool(value)) => Ok(*value),
+        Some(other) => Err(format!("{name} must be true or false, not {other}").into()),
+    }
+}
+
+/// Run git in a directory, returning its trimmed output
+fn git(directory: &Path, args: &[&str]) -> tera::Result<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(directory)
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| tera::Error::msg(format!("Failed to run git: {e}")))?;
+
+    if output.status.success() {
+        return Ok(String::from_utf8_lossy(&output.stdout).trim().to_string());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if stderr.contains("not a git repository") {
+        Err(format!("'{}' is not inside a git repository", directory.display()).into())
+    } else {
+        Err(format!("git {} failed: {}", args.join(" "), stderr.trim()).into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tera::{Context, Tera};
+
+    fn render(directory: &Path, template: &str) -> tera::Result<String> {
+        let mut tera = Tera::default();
+        for (name, query) in GitQuery::ALL {
+            tera.register_function(name, git_function(directory.to_path_buf(), *query));
+        }
+        tera.render_str(template, &Context::new())
+    }
+
+    fn run_git(directory: &Path, args: &[&str]) {
+        let status = Command::new("git")
+            .arg("-C")
+            .arg(directory)
+            .args([
+                "-c",
+                "user.name=floki",
+                "-c",
+                "user.email=floki@example.com",
+            ])
+            .args(args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .expect("failed to run git");
+        assert!(status.success(), "git {:?} failed", args);
+    }
+
+    #[test]
+    fn test_git_functions() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let repo = tmp_dir.path().canonicalize()?;
+        run_git(&repo, &["init", "-q", "-b", "main"]);
+        std::fs::write(repo.join("file"), "content")?;
+        run_git(&repo, &["add", "file"]);
+        run_git(&repo, &["commit", "-q", "-m", "initial"]);
+        run_git(&repo, &["tag", "v1.0"]);
+
+        let rendered = render(
+            &repo,
+            "{{ git_branch() }} {{ git_describe() }} {{ git_is_dirty() }} {{ git_root() }}",
+        )?;
+        assert_eq!(rendered, format!("main v1.0 false {}", repo.display()));
+
+        std::fs::write(repo.join("file"), "changed")?;
+        let rendered = render(&repo, "{{ git_is_dirty() }} {{ git_describe(dirty=true) }}")?;
+        assert_eq!(rendered, "true v1.0-dirty");
+
+        let commit = render(&repo, "{{ git_commit() }}")?;
+        let short = render(&repo, "{{ git_commit(short=true) }}")?;
+        assert_eq!(commit.len(), 40);
+        assert!(commit.starts_with(&short));
+
+        run_git(&repo, &["checkout", "-q", "--detach"]);
+        assert_eq!(render(&repo, "{{ git_branch() }}")?, "HEAD");
+        Ok(())
+    }
+
+    #[test]
+    fn test_git_functions_outside_repository() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        for (name, _) in GitQuery::ALL {
+            let error = render(tmp_dir.path(), &format!("{{{{ {name}() }}}}")).unwrap_err();
+            let message = format!("{:?}", error);
+            assert!(
+                message.contains("is not inside a git repository"),
+                "{}: {}",
+                name,
+                message
+            );
+        }
+        Ok(())
+    }
+}
@@ -1,13 +1,15 @@
 use anyhow::Error;
-use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer, Serialize};
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
-use yaml_rust2::YamlLoader;
 
+use crate::config::{load_yaml, value_at_key, YamlTags};
+use crate::diagnostics::{deserialize_untagged, single_key, Variant};
 use crate::errors::{FlokiError, FlokiSubprocessExitStatus};
 
-#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 pub struct BuildSpec {
     name: String,
     #[serde(default = "default_dockerfile")]
@@ -17,13 +19,13 @@ pub struct BuildSpec {
     target: Option<String>,
 }
 
-#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 pub struct YamlSpec {
     pub file: PathBuf,
     key: String,
 }
 
-#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 pub struct ExecSpec {
     command: String,
     args: Vec<String>,
@@ -38,7 +40,7 @@ fn default_context() -> PathBuf {
     ".".into()
 }
 
-#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Serialize, JsonSchema)]
 #[serde(untagged)]
 pub enum Image {
     Name(String),
@@ -47,6 +49,37 @@ pub enum Image {
     Exec { exec: ExecSpec },
 }
 
+impl<'de> Deserialize<'de> for Image {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_untagged(
+            deserializer,
+            "image",
+            &[
+                Variant {
+                    description: "an image name such as `debian:latest`",
+                    keys: &[],
+                    parse: |v| serde_yaml::from_value(v).map(Image::Name),
+                },
+                Variant {
+                    description: "a map with a `build` spec",
+                    keys: &["build"],
+                    parse: |v| single_key(v, "build").map(|build| Image::Build { build }),
+                },
+                Variant {
+                    description: "a map with a `yaml` spec",
+                    keys: &["yaml"],
+                    parse: |v| single_key(v, "yaml").map(|yaml| Image::Yaml { yaml }),
+                },
+                Variant {
+                    description: "a map with an `exec` spec",
+                    keys: &["exec"],
+                    parse: |v| single_key(v, "exec").map(|exec| Image::Exec { exec }),
+                },
+            ],
+        )
+    }
+}
+
 impl Image {
     /// Name of the image
     pub fn name(&self) -> Result<String, Error> {
@@ -55,19 +88,9 @@ impl Image {
             Image::Build { ref build } => Ok(build.name.clone() + ":floki"),
             Image::Yaml { ref yaml } => {
                 let contents = fs::read_to_string(&yaml.file)?;
-                let raw = YamlLoader::load_from_str(&contents)?;
-                let path = yaml.key.split('.').collect::<Vec<_>>();
-                let mut val = &raw[0];
-
-                for key in &path {
-                    // Yaml arrays and maps with scalar keys can both be indexed by
-                    // usize, so heuristically prefer a usize index to a &str index.
-                    val = match key.parse::<usize>() {
-                        Ok(x) => &val[x],
-                        Err(_) => &val[*key],
-                    };
-                }
-                val.as_str()
+                let document = load_yaml(&contents, YamlTags::Strip).map_err(Error::msg)?;
+                value_at_key(&document, &yaml.key)
+                    .and_then(serde_yaml::Value::as_str)
                     .map(std::string::ToString::to_string)
                     .ok_or_else(|| {
                         FlokiError::FailedToFindYamlKey {
@@ -81,6 +104,28 @@ impl Image {
         }
     }
 
+    /// Host paths the image depends on, which must exist to obtain it
+    pub(crate) fn input_paths(&self, floki_root: &Path) -> Vec<PathBuf> {
+        match self {
+            Image::Build { build } => vec![
+                floki_root.join(&build.dockerfile),
+                floki_root.join(&build.context),
+            ],
+            Image::Yaml { yaml } => vec![yaml.file.clone()],
+            _ => Vec::new(),
+        }
+    }
+
+    /// The command run on the host to obtain the image, if any
+    pub(crate) fn host_command(&self) -> Option<String> {
+        match self {
+            Image::Exec { exec } => Some(shell_words::join(
+                std::iter::once(&exec.command).chain(&exec.args),
+            )),
+            _ => None,
+        }
+    }
+
     /// Do the required work to get the image, and then return
     /// it's name
     pub fn obtain_image(&self, floki_root: &Path) -> Result<String, Error> {
@@ -240,4 +285,19 @@ image:
         let actual: TestImage = serde_yaml::from_str(yaml).unwrap();
         assert!(actual == expected);
     }
+
+    #[test]
+    fn test_image_name_from_yaml() -> Result<(), Error> {
+        let image = |key: &str| Image::Yaml {
+            yaml: YamlSpec {
+                file: "test_resources/gitlab_reference.yaml".into(),
+                key: key.into(),
+            },
+        };
+        assert_eq!(image("build.script.2").name()?, "echo build");
+        // Tags are stripped, as when loading the file in a template.
+        assert_eq!(image("job.script.1").name()?, "script");
+        assert!(image("job.missing").name().is_err());
+        Ok(())
+    }
 }
@@ -1,25 +1,46 @@
 use crate::command;
 use crate::command::DockerCommandBuilder;
+use crate::config::Owner;
 use crate::dind::Dind;
+use crate::seed::seed_volume;
 use crate::spec;
-use crate::volumes::resolve_volume_mounts;
+use crate::volumes::{
+    chown_volume, ensure_docker_volume, mount_volume, record_volume_use, remove_source,
+    resolve_volume_mounts, ResolvedVolume, VolumeSource,
+};
 
 use anyhow::Error;
-use std::path;
+
+/// The command to run in the floki container
+#[derive(Debug, PartialEq)]
+pub(crate) enum ContainerCommand {
+    /// Shell code to run after the init commands, via the outer shell
+    Shell(String),
+    /// An argument vector to exec exactly, without passing it through
+    /// the inner shell
+    Exec(Vec<String>),
+}
 
 pub(crate) fn run_floki_container(
     spec: &spec::FlokiSpec,
-    inner_command: &str,
+    command: &ContainerCommand,
 ) -> Result<(), Error> {
     spec.image.obtain_image(&spec.paths.root)?;
 
     let mut cmd = command::DockerCommandBuilder::new(&spec.image.name()?)
+        .set_tty(spec.terminal.tty)
+        .set_interactive(spec.terminal.interactive)
         .add_volume((&spec.paths.root, &spec.mount));
 
     let volumes = resolve_volume_mounts(&spec.paths.config, &spec.paths.workspace, &spec.volumes);
-    instantiate_volumes(&volumes)?;
+    instantiate_volumes(spec, &volumes)?;
 
     cmd = configure_volumes(cmd, &volumes);
+
+    for mount in &spec.mounts {
+        cmd = cmd.add_mount(mount.mount_argument());
+    }
+
     cmd = cmd.add_environment("FLOKI_HOST_MOUNTDIR", &spec.paths.root);
     cmd = cmd.add_environment("FLOKI_HOST_UID", spec.user.uid.to_string());
     cmd = cmd.add_environment("FLOKI_HOST_GID", spec.user.gid.to_string());
@@ -54,37 +75,119 @@ pub(crate) fn run_floki_container(
         None
     };
 
-    // Calculate the outer shell command.
-    let subshell_command = subshell_command(&spec.init, inner_command);
-    let mut outer_shell_cmd = shell_words::split(spec.shell.outer_shell())?;
-    outer_shell_cmd.push("-c".to_string());
-    outer_shell_cmd.push(subshell_command);
-
-    cmd.run(outer_shell_cmd)
+    let result = cmd.run(container_args(spec, command)?);
+    // Report how the container exited in preference to any problem fixing
+    // ownership afterwards
+    let fixed = fix_volume_ownership(spec, &volumes);
+    if let Err(e) = &fixed {
+        warn!("Unable to fix ownership of volumes: {e}");
+    }
+    result.and(fixed)
 }
 
+/// Build a shell command which runs the given argument vector in the
+/// inner shell, quoting it so that every argument reaches the command
+/// exactly as given.
 pub(crate) fn command_in_shell(shell: &str, command: &[String]) -> String {
     // Make sure our command runs in a subshell (we might switch user)
-    let inner_shell: String = shell.to_string();
-    inner_shell + " -c \"" + &command.join(" ") + "\""
+    shell.to_string() + " -c " + &shell_words::quote(&shell_words::join(command))
+}
+
+/// Calculate the arguments given to docker to run in the container
+fn container_args(
+    spec: &spec::FlokiSpec,
+    command: &ContainerCommand,
+) -> Result<Vec<String>, Error> {
+    let (subshell_command, positional) = match command {
+        ContainerCommand::Shell(inner_command) => {
+            (subshell_command(&spec.init, inner_command), None)
+        }
+        // Without any init commands there's no need for a shell at all.
+        ContainerCommand::Exec(argv) if spec.init.is_empty() => return Ok(argv.clone()),
+        // Pass the arguments as positional parameters to the outer shell
+        // so they are never parsed as shell code.
+        ContainerCommand::Exec(argv) => (subshell_command(&spec.init, "exec \"$@\""), Some(argv)),
+    };
+
+    let mut outer_shell_cmd = shell_words::split(spec.shell.outer_shell())?;
+    outer_shell_cmd.push("-c".to_string());
+    outer_shell_cmd.push(subshell_command);
+    if let Some(argv) = positional {
+        // The first argument after the command is the shell's $0.
+        outer_shell_cmd.push("floki".to_string());
+        outer_shell_cmd.extend(argv.iter().cloned());
+    }
+    Ok(outer_shell_cmd)
 }
 
 /// Add mounts for each of the passed in volumes
 fn configure_volumes(
     cmd: DockerCommandBuilder,
-    volumes: &[(path::PathBuf, &path::PathBuf)],
+    volumes: &[ResolvedVolume],
 ) -> DockerCommandBuilder {
     let mut cmd = cmd; // Shadow as mutable
-    for (src, dst) in volumes.iter() {
-        cmd = cmd.add_volume((src, dst));
+    for volume in volumes.iter() {
+        cmd = mount_volume(cmd, volume, &volume.config.mount);
     }
     cmd
 }
 
-/// Create the backing directories for floki volumes if needed
-fn instantiate_volumes(volumes: &[(path::PathBuf, &path::PathBuf)]) -> Result<(), Error> {
-    for (src, _) in volumes.iter() {
-        std::fs::create_dir_all(src)?;
+/// Reset the ownership of volumes which ask for it after a run
+fn fix_volume_ownership(spec: &spec::FlokiSpec, volumes: &[ResolvedVolume]) -> Result<(), Error> {
+    for volume in volumes.iter().filter(|v| v.config.fix_ownership) {
+        let (uid, gid) = volume_owner(spec, volume);
+        chown_volume(volume, &spec.image.name()?, uid, gid)?;
+    }
+    Ok(())
+}
+
+/// The configured owner of a volume, defaulting to the host user
+fn volume_owner(spec: &spec::FlokiSpec, volume: &ResolvedVolume) -> (u32, u32) {
+    volume
+        .config
+        .owner
+        .unwrap_or(Owner::User)
+        .ids(spec.user.uid.as_raw(), spec.user.gid.as_raw())
+}
+
+/// Create the backing directories or docker volumes for floki volumes if
+/// needed, seeding any which are newly created, and record that they
+/// have been used
+fn instantiate_volumes(spec: &spec::FlokiSpec, volumes: &[ResolvedVolume]) -> Result<(), Error> {
+    let paths = &spec.paths;
+    for volume in volumes.iter() {
+        let created = match &volume.source {
+            VolumeSource::Directory(src) => {
+                let created = !src.exists();
+                std::fs::create_dir_all(src)?;
+                created
+            }
+            VolumeSource::Docker(name) => ensure_docker_volume(name, volume.name, &paths.config)?,
+        };
+        if created {
+            // Remove the half-prepared volume on failure so it is
+            // prepared again on the next run.
+            if let Err(e) = prepare_volume(spec, volume) {
+                remove_source(&volume.source)?;
+                return Err(e);
+            }
+        }
+        record_volume_use(&paths.workspace, &paths.config, volume)?;
+    }
+    Ok(())
+}
+
+/// Seed a newly created volume and set its owner
+fn prepare_volume(spec: &spec::FlokiSpec, volume: &ResolvedVolume) -> Result<(), Error> {
+    let image = spec.image.name()?;
+    seed_volume(volume, &image, &spec.paths.root)?;
+    if volume.config.owner.is_some() {
+        let (uid, gid) = volume_owner(spec, volume);
+        let owned_by_host_user = matches!(volume.source, VolumeSource::Directory(_))
+            && (uid, gid) == (spec.user.uid.as_raw(), spec.user.gid.as_raw());
+        if !owned_by_host_user {
+            chown_volume(volume, &image, uid, gid)?;
+        }
     }
     Ok(())
 }
@@ -106,8 +209,52 @@ mod test {
         let subcommand = vec![String::from("foo"), String::from("bar")];
 
         let result = command_in_shell("bash", &subcommand);
-        let expected = String::from("bash -c \"foo bar\"");
+        let expected = String::from("bash -c 'foo bar'");
 
         assert!(result == expected);
     }
+
+    /// Run a shell command line locally and return what it printed
+    fn run_in_sh(args: &[String]) -> String {
+        let output = std::process::Command::new(&args[0])
+            .args(&args[1..])
+            .output()
+            .expect("failed to run sh");
+        String::from_utf8(output.stdout).expect("output was not utf-8")
+    }
+
+    fn awkward_argv() -> Vec<String> {
+        vec![
+            "printf".into(),
+            "[%s]".into(),
+            "a b".into(),
+            "$HOME".into(),
+            "it's \"quoted\"".into(),
+            "back\\slash".into(),
+            "".into(),
+        ]
+    }
+
+    #[test]
+    fn test_command_in_shell_preserves_arguments() {
+        let inner = command_in_shell("sh", &awkward_argv());
+        let subshell = subshell_command(&["true".to_string()], &inner);
+
+        let output = run_in_sh(&["sh".into(), "-c".into(), subshell]);
+
+        assert_eq!(output, "[a b][$HOME][it's \"quoted\"][back\\slash][]");
+    }
+
+    #[test]
+    fn test_exec_command_after_init_preserves_arguments() {
+        let mut args = vec!["sh".to_string(), "-c".to_string()];
+        args.push(subshell_command(&["true".to_string()], "exec \"$@\""));
+        args.push("floki".into());
+        args.extend(awkward_argv());
+
+        assert_eq!(
+            run_in_sh(&args),
+            "[a b][$HOME][it's \"quoted\"][back\\slash][]"
+        );
+    }
 }
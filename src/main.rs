@@ -5,19 +5,32 @@ extern crate log;
 mod cli;
 mod command;
 mod config;
+mod diagnostics;
 mod dind;
 mod environment;
 mod errors;
+mod files;
+mod git;
 mod image;
 mod interpret;
+mod merge;
+mod mounts;
+mod policy;
+mod sandbox;
+mod seed;
 mod spec;
+mod templates;
+mod trust;
+mod validate;
+mod vars;
 mod volumes;
 
 use anyhow::Error;
-use cli::{Cli, Subcommand};
-use config::FlokiConfig;
+use cli::{Cli, Subcommand, VolumeCommand};
+use config::{FlokiConfig, Owner, TemplateContext, UserConfig};
 use environment::Environment;
 use errors::FlokiError;
+use interpret::ContainerCommand;
 use structopt::StructOpt;
 
 fn main() -> Result<(), Error> {
@@ -46,17 +59,24 @@ fn run_floki_from_args(args: &Cli) -> Result<(), Error> {
     match &args.subcommand {
         // Pull the image in the configuration file
         Some(Subcommand::Pull {}) => {
-            let env = Environment::gather(&args.config_file)?;
-            let config = FlokiConfig::from_file(&env.config_file)?;
+            let env = Environment::gather(args)?;
+            let config = load_config(&env)?;
             image::pull_image(&config.image.name()?)
         }
 
         // Run a command in the floki container
-        Some(Subcommand::Run { command }) => {
-            let env = Environment::gather(&args.config_file)?;
-            let config = FlokiConfig::from_file(&env.config_file)?;
-            let inner_command = interpret::command_in_shell(config.shell.inner_shell(), command);
-            interpret::run_floki_container(&spec::FlokiSpec::from(config, env)?, &inner_command)
+        Some(Subcommand::Run { no_shell, command }) => {
+            let env = Environment::gather(args)?;
+            let config = load_trusted_config(&env)?;
+            let container_command = if *no_shell {
+                ContainerCommand::Exec(command.clone())
+            } else {
+                ContainerCommand::Shell(interpret::command_in_shell(
+                    config.shell.inner_shell(),
+                    command,
+                ))
+            };
+            interpret::run_floki_container(&container_spec(config, env)?, &container_command)
         }
 
         Some(Subcommand::Completion { shell }) => {
@@ -64,21 +84,261 @@ fn run_floki_from_args(args: &Cli) -> Result<(), Error> {
             Ok(())
         }
 
-        Some(Subcommand::Render {}) => {
-            let env = Environment::gather(&args.config_file)?;
-            let contents = FlokiConfig::render(&env.config_file)?;
-            println!("{contents}");
+        Some(Subcommand::Render { output, explain }) => {
+            let env = Environment::gather(args)?;
+            let mut context = env.template_context();
+            load_user_config(&env, &mut context)?;
+            if *explain || !env.override_files.is_empty() {
+                let merged = FlokiConfig::merge_files(
+                    &env.config_file,
+                    env.config_format,
+                    &env.override_files,
+                    &context,
+                )?;
+                if *explain {
+                    print!("{}", merged.explain());
+                } else {
+                    print!(
+                        "{}",
+                        output
+                            .unwrap_or(env.config_format)
+                            .serialize(&merged.value)?
+                    );
+                }
+                return Ok(());
+            }
+            let contents = FlokiConfig::render(&env.config_file, &context)?;
+            match output {
+                Some(format) => print!(
+                    "{}",
+                    env.config_format.convert(
+                        &contents,
+                        &env.config_file.display().to_string(),
+                        *format
+                    )?
+                ),
+                None => println!("{contents}"),
+            }
             Ok(())
         }
 
+        Some(Subcommand::Schema {}) => {
+            let schema = schemars::schema_for!(FlokiConfig);
+            println!("{}", serde_json::to_string_pretty(&schema)?);
+            Ok(())
+        }
+
+        Some(Subcommand::Validate {}) => {
+            let mut env = Environment::gather(args)?;
+            let config = load_config(&env)?;
+            let problems = validate::validate(&config, &mut env);
+            if problems.is_empty() {
+                println!("{} is valid", env.config_file.display());
+                return Ok(());
+            }
+            for problem in &problems {
+                println!("{problem}");
+            }
+            Err(FlokiError::InvalidConfig {
+                name: env.config_file.display().to_string(),
+                count: problems.len(),
+            }
+            .into())
+        }
+
+        Some(Subcommand::Trust {}) => {
+            let env = Environment::gather(args)?;
+            let (config, _) = load_project_config(&env)?;
+            trust::TrustStore::new(&env.floki_workspace).trust(&env.config_file, &config)?;
+            println!("Trusted {}", env.config_file.display());
+            for risk in trust::risks(&env.config_file, &config) {
+                println!("  - it may {risk}");
+            }
+            Ok(())
+        }
+
+        Some(Subcommand::Untrust {}) => {
+            let env = Environment::gather(args)?;
+            if trust::TrustStore::new(&env.floki_workspace).untrust(&env.config_file)? {
+                println!("{} is no longer trusted", env.config_file.display());
+            } else {
+                println!("{} was not trusted", env.config_file.display());
+            }
+            Ok(())
+        }
+
+        Some(Subcommand::Info {}) => {
+            let mut env = Environment::gather(args)?;
+            let config = load_config(&env)?;
+            env.apply_config_root(config.root.as_deref())?;
+            let working_directory = spec::get_working_directory(
+                &env.current_directory,
+                &env.floki_root,
+                &config.mount,
+                config.workdir.as_deref(),
+            )?;
+            println!("Configuration file:  {}", env.config_file.display());
+            for override_file in &env.override_files {
+                println!("Override file:       {}", override_file.display());
+            }
+            if let Some(user_config) = &env.user_config_file {
+                println!("User configuration:  {}", user_config.display());
+            }
+            for policy_file in &env.policy_files {
+                println!("Policy file:         {}", policy_file.display());
+            }
+            println!(
+                "Floki root:          {} ({})",
+                env.floki_root.display(),
+                env.root_source
+            );
+            println!("Mounted at:          {}", config.mount.display());
+            println!("Working directory:   {}", working_directory.display());
+            println!("Floki workspace:     {}", env.floki_workspace.display());
+            Ok(())
+        }
+
+        Some(Subcommand::Volume { command }) => run_volume_command(args, command),
+
         // Launch an interactive floki shell (the default)
         None => {
-            let env = Environment::gather(&args.config_file)?;
-            let config = FlokiConfig::from_file(&env.config_file)?;
-            let inner_command = config.shell.inner_shell().to_string();
-            interpret::run_floki_container(&spec::FlokiSpec::from(config, env)?, &inner_command)
+            let env = Environment::gather(args)?;
+            let config = load_trusted_config(&env)?;
+            let inner_command = ContainerCommand::Shell(config.shell.inner_shell().to_string());
+            interpret::run_floki_container(&container_spec(config, env)?, &inner_command)
+        }
+    }
+}
+
+/// Load the configuration file, with the user's defaults applied
+fn load_config(env: &Environment) -> Result<FlokiConfig, Error> {
+    let (mut config, user_config) = load_project_config(env)?;
+    if let Some(user_config) = user_config {
+        config.apply_user_config(user_config);
+    }
+    Ok(config)
+}
+
+/// Load the configuration file, checking the user trusts it to do anything
+/// risky before applying the user's defaults
+fn load_trusted_config(env: &Environment) -> Result<FlokiConfig, Error> {
+    let (mut config, user_config) = load_project_config(env)?;
+    trust::ensure_trusted(&env.floki_workspace, &env.config_file, &config)?;
+    if let Some(user_config) = user_config {
+        config.apply_user_config(user_config);
+    }
+    Ok(config)
+}
+
+/// Load the configuration file and the user configuration file, without
+/// applying one to the other
+fn load_project_config(env: &Environment) -> Result<(FlokiConfig, Option<UserConfig>), Error> {
+    let mut context = env.template_context();
+    let user_config = load_user_config(env, &mut context)?;
+    let config = FlokiConfig::from_file(
+        &env.config_file,
+        env.config_format,
+        &env.override_files,
+        &context,
+    )?;
+    Ok((config, user_config))
+}
+
+/// Resolve the configuration into a spec for running a container, checking
+/// it against the policy files
+fn container_spec(config: FlokiConfig, env: Environment) -> Result<spec::FlokiSpec, Error> {
+    let policies = policy::Policies::load(&env.policy_files)?;
+    let spec = spec::FlokiSpec::from(config, env)?;
+    policies.check(&spec)?;
+    Ok(spec)
+}
+
+/// Load the user configuration file, if there is one, and allow templates
+/// to read the directories it lists
+fn load_user_config(
+    env: &Environment,
+    context: &mut TemplateContext,
+) -> Result<Option<UserConfig>, Error> {
+    let file = match &env.user_config_file {
+        Some(file) => file,
+        None => return Ok(None),
+    };
+    let user_config = UserConfig::from_file(file, context)?;
+    let directory = file.parent().unwrap_or_else(|| std::path::Path::new("/"));
+    for path in &user_config.allow_template_paths {
+        let expanded = mounts::expand_path(&path.to_string_lossy(), |var| std::env::var(var).ok())
+            .map_err(|e| anyhow::anyhow!("allow_template_paths in {}: {e}", file.display()))?;
+        context.allowed_paths.push(directory.join(expanded));
+    }
+    Ok(Some(user_config))
+}
+
+/// Run one of the volume management subcommands
+fn run_volume_command(args: &Cli, command: &VolumeCommand) -> Result<(), Error> {
+    match command {
+        VolumeCommand::List {} => volumes::list_volumes(&environment::floki_workspace()),
+        VolumeCommand::Prune {
+            dangling: true,
+            older_than: _,
+        } => volumes::prune_docker_volumes(&environment::floki_workspace()),
+        VolumeCommand::Prune {
+            dangling: false,
+            older_than,
+        } => volumes::prune_volumes(&environment::floki_workspace(), *older_than),
+        VolumeCommand::Inspect { name } => {
+            with_configured_volume(args, name, |_, volume| volumes::inspect_volume(volume))
+        }
+        VolumeCommand::Clear { name } => with_configured_volume(args, name, |env, volume| {
+            volumes::clear_volume(&env.floki_workspace, volume)
+        }),
+        VolumeCommand::Chown { name, owner } => chown_volumes(args, name.as_deref(), *owner),
+    }
+}
+
+/// Change the owner of one or all of the volumes in the configuration file
+fn chown_volumes(args: &Cli, name: Option<&str>, owner: Option<Owner>) -> Result<(), Error> {
+    let mut env = Environment::gather(args)?;
+    // Obtaining the image may run a command on the host
+    let config = load_trusted_config(&env)?;
+    env.apply_config_root(config.root.as_deref())?;
+    // The image is run as root, so must be allowed by the policies too
+    policy::Policies::load(&env.policy_files)?.check_image(&config.image.name()?)?;
+    let image = config.image.obtain_image(&env.floki_root)?;
+    let volumes = match name {
+        Some(name) => vec![volumes::resolve_volume(
+            &env.config_file,
+            &env.floki_workspace,
+            &config.volumes,
+            name,
+        )?],
+        None => {
+            volumes::resolve_volume_mounts(&env.config_file, &env.floki_workspace, &config.volumes)
         }
+    };
+    for volume in volumes {
+        let (uid, gid) = owner
+            .or(volume.config.owner)
+            .unwrap_or(Owner::User)
+            .ids(env.user_details.uid.as_raw(), env.user_details.gid.as_raw());
+        volumes::chown_volume(&volume, &image, uid, gid)?;
     }
+    Ok(())
+}
+
+/// Look up a volume from the configuration file and act on it
+fn with_configured_volume<F>(args: &Cli, name: &str, action: F) -> Result<(), Error>
+where
+    F: FnOnce(&Environment, &volumes::ResolvedVolume) -> Result<(), Error>,
+{
+    let env = Environment::gather(args)?;
+    let config = load_config(&env)?;
+    let volume = volumes::resolve_volume(
+        &env.config_file,
+        &env.floki_workspace,
+        &config.volumes,
+        name,
+    )?;
+    action(&env, &volume)
 }
 
 /// Configure the logger
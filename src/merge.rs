@@ -0,0 +1,279 @@
+/// Merging of local override files over the floki configuration
+use serde_json::{Map, Value};
+use serde_yaml::{Mapping as YamlMapping, Value as YamlValue};
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// The override files floki applies over the configuration file, in the
+/// order they are applied. They live next to the configuration file and
+/// are intended to be left out of version control.
+pub const OVERRIDE_FILE_NAMES: &[&str] = &["floki.override.yaml", "floki.local.yaml"];
+
+/// Marks a value from an override file which was tagged `!override`, and
+/// so replaces the configuration's value instead of being merged with it
+const OVERRIDE_MARKER: &str = "!override";
+
+/// Find the override files which exist next to a configuration file
+pub(crate) fn find_override_files(config_directory: &Path) -> Vec<PathBuf> {
+    OVERRIDE_FILE_NAMES
+        .iter()
+        .map(|name| config_directory.join(name))
+        .filter(|f| f.is_file())
+        .collect()
+}
+
+/// A configuration built up from several files, remembering which file
+/// each value came from
+#[derive(Debug, PartialEq)]
+pub(crate) struct MergedConfig {
+    /// The merged configuration
+    pub(crate) value: Value,
+    /// The file which set each leaf value, keyed by its path in the
+    /// configuration (e.g. `volumes.cache.mount` or `init[1]`)
+    pub(crate) origins: BTreeMap<String, PathBuf>,
+}
+
+impl MergedConfig {
+    /// Start from a single configuration file
+    pub(crate) fn new(file: &Path, value: Value) -> Self {
+        let mut merged = MergedConfig {
+            value: Value::Null,
+            origins: BTreeMap::new(),
+        };
+        merged.merge(file, value);
+        merged
+    }
+
+    /// Deep-merge a file's values over the configuration. Maps are merged
+    /// key by key, lists are extended, other values are replaced, and a
+    /// null value removes the key. Values marked by `override_value` are
+    /// replaced outright.
+    pub(crate) fn merge(&mut self, file: &Path, value: Value) {
+        let base = std::mem::replace(&mut self.value, Value::Null);
+        self.value = merge_value(base, value, "", file, &mut self.origins);
+    }
+
+    /// List every leaf value in the configuration alongside the file it
+    /// came from
+    pub(crate) fn explain(&self) -> String {
+        let mut leaves = Vec::new();
+        collect_leaves(&self.value, "", &mut leaves);
+        let width = leaves.iter().map(|(l, _)| l.len()).max().unwrap_or(0);
+        leaves
+            .iter()
+            .map(|(line, key)| {
+                let origin = self
+                    .origins
+                    .get(key)
+                    .map(|f| f.display().to_string())
+                    .unwrap_or_default();
+                format!("{line:width$}  # {origin}\n")
+            })
+            .collect()
+    }
+}
+
+fn merge_value(
+    base: Value,
+    overlay: Value,
+    key: &str,
+    file: &Path,
+    origins: &mut BTreeMap<String, PathBuf>,
+) -> Value {
+    if let Some(replacement) = overridden(&overlay) {
+        forget(origins, key);
+        record(replacement, key, file, origins);
+        return replacement.clone();
+    }
+    match (base, overlay) {
+        (Value::Object(mut base), Value::Object(overlay)) => {
+            for (name, value) in overlay {
+                let child_key = child_key(key, &name);
+                if value.is_null() {
+                    base.remove(&name);
+                    forget(origins, &child_key);
+                    continue;
+                }
+                let existing = base.remove(&name).unwrap_or(Value::Null);
+                base.insert(
+                    name,
+                    merge_value(existing, value, &child_key, file, origins),
+                );
+            }
+            Value::Object(base)
+        }
+        (Value::Array(mut base), Value::Array(overlay)) => {
+            for value in overlay {
+                let child_key = format!("{key}[{}]", base.len());
+                base.push(merge_value(Value::Null, value, &child_key, file, origins));
+            }
+            Value::Array(base)
+        }
+        (_, overlay) => {
+            forget(origins, key);
+            record(&overlay, key, file, origins);
+            overlay
+        }
+    }
+}
+
+/// The replacement for a value, if it was tagged `!override`
+fn overridden(value: &Value) -> Option<&Value> {
+    match value {
+        Value::Object(map) if map.len() == 1 => map.get(OVERRIDE_MARKER),
+        _ => None,
+    }
+}
+
+/// Convert an override file into a value to merge over the configuration,
+/// marking values tagged `!override` (as in docker compose) so that they
+/// replace the configuration's value
+pub(crate) fn override_value(value: YamlValue) -> Result<Value, serde_yaml::Error> {
+    serde_yaml::from_value(mark_overrides(value))
+}
+
+fn mark_overrides(value: YamlValue) -> YamlValue {
+    match value {
+        YamlValue::Tagged(tagged) if tagged.tag == "override" => {
+            let mut marked = YamlMapping::new();
+            marked.insert(OVERRIDE_MARKER.into(), mark_overrides(tagged.value));
+            YamlValue::Mapping(marked)
+        }
+        YamlValue::Sequence(items) => {
+            YamlValue::Sequence(items.into_iter().map(mark_overrides).collect())
+        }
+        YamlValue::Mapping(map) => YamlValue::Mapping(
+            map.into_iter()
+                .map(|(key, value)| (key, mark_overrides(value)))
+                .collect(),
+        ),
+        other => other,
+    }
+}
+
+/// Record the origin of every leaf in a newly set value
+fn record(value: &Value, key: &str, file: &Path, origins: &mut BTreeMap<String, PathBuf>) {
+    match value {
+        Value::Object(map) => {
+            for (name, child) in map {
+                record(child, &child_key(key, name), file, origins);
+            }
+        }
+        Value::Array(items) => {
+            for (i, child) in items.iter().enumerate() {
+                record(child, &format!("{key}[{i}]"), file, origins);
+            }
+        }
+        _ => {
+            origins.insert(key.to_string(), file.to_path_buf());
+        }
+    }
+}
+
+/// Forget the origins of a value and everything beneath it
+fn forget(origins: &mut BTreeMap<String, PathBuf>, key: &str) {
+    origins.retain(|k, _| {
+        !(k == key
+            || k.strip_prefix(key)
+                .is_some_and(|rest| rest.starts_with('.') || rest.starts_with('[')))
+    });
+}
+
+fn child_key(key: &str, name: &str) -> String {
+    if key.is_empty() {
+        name.to_string()
+    } else {
+        format!("{key}.{name}")
+    }
+}
+
+fn collect_leaves(value: &Value, key: &str, leaves: &mut Vec<(String, String)>) {
+    match value {
+        Value::Object(map) if !map.is_empty() => {
+            for (name, child) in map {
+                collect_leaves(child, &child_key(key, name), leaves);
+            }
+        }
+        Value::Array(items) if !items.is_empty() => {
+            for (i, child) in items.iter().enumerate() {
+                collect_leaves(child, &format!("{key}[{i}]"), leaves);
+            }
+        }
+        _ => leaves.push((format!("{key} = {value}"), key.to_string())),
+    }
+}
+
+/// Convert a parsed configuration into a map, treating an empty document
+/// as an empty map
+pub(crate) fn as_object(value: Value) -> Value {
+    match value {
+        Value::Null => Value::Object(Map::new()),
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_merge_overrides() {
+        let base = Path::new("floki.yaml");
+        let local = Path::new("floki.local.yaml");
+        let mut merged = MergedConfig::new(
+            base,
+            json!({"image": "debian:stable", "init": ["a"], "volumes": {"cache": {"mount": "/c", "shared": true}}}),
+        );
+        merged.merge(
+            local,
+            json!({"image": "debian:testing", "init": ["b"], "volumes": {"cache": {"shared": null}}}),
+        );
+
+        assert_eq!(
+            merged.value,
+            json!({"image": "debian:testing", "init": ["a", "b"], "volumes": {"cache": {"mount": "/c"}}})
+        );
+        assert_eq!(merged.origins["image"], local);
+        assert_eq!(merged.origins["init[0]"], base);
+        assert_eq!(merged.origins["init[1]"], local);
+        assert_eq!(merged.origins["volumes.cache.mount"], base);
+        assert!(!merged.origins.contains_key("volumes.cache.shared"));
+    }
+
+    #[test]
+    fn test_merge_replaces_structured_values() {
+        let base = Path::new("floki.yaml");
+        let local = Path::new("floki.local.yaml");
+        let mut merged = MergedConfig::new(base, json!({"image": {"build": {"name": "foo"}}}));
+        merged.merge(local, json!({"image": "debian"}));
+
+        assert_eq!(merged.value, json!({"image": "debian"}));
+        assert_eq!(merged.origins.len(), 1);
+        assert_eq!(merged.explain(), "image = \"debian\"  # floki.local.yaml\n");
+    }
+
+    #[test]
+    fn test_merge_override_tag_replaces_values() -> Result<(), Box<dyn std::error::Error>> {
+        let base = Path::new("floki.yaml");
+        let local = Path::new("floki.local.yaml");
+        let mut merged = MergedConfig::new(
+            base,
+            json!({"init": ["a", "b"], "mounts": [{"source": "/x", "target": "/x"}], "volumes": {"cache": {"mount": "/c", "shared": true}}}),
+        );
+        let overlay: YamlValue = serde_yaml::from_str(
+            "init: !override [c]\nmounts: !override []\nvolumes:\n  cache: !override\n    mount: /d\n",
+        )?;
+        merged.merge(local, override_value(overlay)?);
+
+        assert_eq!(
+            merged.value,
+            json!({"init": ["c"], "mounts": [], "volumes": {"cache": {"mount": "/d"}}})
+        );
+        assert_eq!(merged.origins["init[0]"], local);
+        assert!(!merged.origins.contains_key("init[1]"));
+        assert!(!merged.origins.contains_key("volumes.cache.shared"));
+        Ok(())
+    }
+}
@@ -0,0 +1,255 @@
+/// Resolution of structured mounts from the floki configuration
+use crate::config::{Mount, MountType};
+use crate::errors::FlokiError;
+
+use anyhow::Error;
+use std::env;
+use std::path;
+
+/// A mount which has been validated and is ready to be passed to docker
+#[derive(Debug, PartialEq)]
+pub(crate) struct ResolvedMount {
+    /// The type of mount
+    pub(crate) mount_type: MountType,
+    /// Host path for bind mounts, volume name for volume mounts
+    pub(crate) source: Option<String>,
+    /// Path inside the container
+    pub(crate) target: path::PathBuf,
+    /// Whether the mount is read-only
+    pub(crate) readonly: bool,
+}
+
+impl ResolvedMount {
+    /// Build the argument for docker's `--mount` switch
+    pub(crate) fn mount_argument(&self) -> String {
+        let mount_type = match self.mount_type {
+            MountType::Bind => "bind",
+            MountType::Tmpfs => "tmpfs",
+            MountType::Volume => "volume",
+        };
+        let mut fields = vec![format!("type={mount_type}")];
+        if let Some(source) = &self.source {
+            fields.push(format!("source={source}"));
+        }
+        fields.push(format!("target={}", self.target.display()));
+        if self.readonly {
+            fields.push("readonly".to_string());
+        }
+        fields
+            .iter()
+            .map(|f| quote_field(f))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+/// Docker parses `--mount` as a CSV record, so quote any field
+/// which contains a comma or a quote.
+fn quote_field(field: &str) -> String {
+    if field.contains(',') || field.contains('"') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Validate the configured mounts, expanding host paths and dropping
+/// optional mounts whose source doesn't exist.
+pub(crate) fn resolve_mounts(
+    mounts: &[Mount],
+    floki_root: &path::Path,
+) -> Result<Vec<ResolvedMount>, Error> {
+    let mut resolved = Vec::new();
+    for mount in mounts {
+        if let Some(m) = resolve_mount(mount, floki_root)? {
+            resolved.push(m);
+        }
+    }
+    Ok(resolved)
+}
+
+fn resolve_mount(mount: &Mount, floki_root: &path::Path) -> Result<Option<ResolvedMount>, Error> {
+    let target = mount.target.display().to_string();
+    let invalid = |reason: &str| FlokiError::InvalidMount {
+        target: target.clone(),
+        reason: reason.to_string(),
+    };
+
+    if !mount.target.is_absolute() {
+        return Err(invalid("the target must be an absolute path").into());
+    }
+
+    let source = match (mount.mount_type, &mount.source) {
+        (MountType::Bind, None) => return Err(invalid("bind mounts require a source").into()),
+        (MountType::Volume, None) => return Err(invalid("volume mounts require a source").into()),
+        (MountType::Tmpfs, Some(_)) => {
+            return Err(invalid("tmpfs mounts do not take a source").into())
+        }
+        (MountType::Tmpfs, None) => None,
+        (MountType::Volume, Some(name)) => Some(name.clone()),
+        (MountType::Bind, Some(source)) => {
+            let expanded =
+                expand_path(source, |var| env::var(var).ok()).map_err(|reason| invalid(&reason))?;
+            let host_path = floki_root.join(expanded);
+            if !host_path.exists() {
+                if mount.optional {
+                    info!(
+                        "Skipping optional mount of '{}' as it does not exist",
+                        host_path.display()
+                    );
+                    return Ok(None);
+                }
+                return Err(FlokiError::MountSourceNotFound {
+                    path: host_path.display().to_string(),
+                    target,
+                }
+                .into());
+            }
+            Some(host_path.display().to_string())
+        }
+    };
+
+    Ok(Some(ResolvedMount {
+        mount_type: mount.mount_type,
+        source,
+        target: mount.target.clone(),
+        readonly: mount.readonly,
+    }))
+}
+
+/// Expand a leading `~` and any `$VAR` or `${VAR}` references in a path.
+pub(crate) fn expand_path<F>(source: &str, lookup: F) -> Result<String, String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let lookup_var = |name: &str| {
+        lookup(name).ok_or_else(|| format!("environment variable '{name}' is not set"))
+    };
+
+    let rest = if source == "~" || source.starts_with("~/") {
+        let home = lookup_var("HOME")?;
+        home + &source[1..]
+    } else {
+        source.to_string()
+    };
+
+    let mut expanded = String::new();
+    let mut chars = rest.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            expanded.push(c);
+            continue;
+        }
+        let mut name = String::new();
+        if chars.peek() == Some(&'{') {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('}') => break,
+                    Some(c) => name.push(c),
+                    None => return Err(format!("unterminated variable reference in '{source}'")),
+                }
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c.is_ascii_alphanumeric() || c == '_' {
+                    name.push(c);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+        }
+        if name.is_empty() {
+            expanded.push('$');
+        } else {
+            expanded += &lookup_var(&name)?;
+        }
+    }
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "HOME" => Some("/home/floki".into()),
+            "CACHE" => Some("cache".into()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_expand_path_home() {
+        assert_eq!(
+            expand_path("~/.gitconfig", lookup),
+            Ok("/home/floki/.gitconfig".into())
+        );
+        assert_eq!(expand_path("~", lookup), Ok("/home/floki".into()));
+        assert_eq!(expand_path("/tmp/~", lookup), Ok("/tmp/~".into()));
+    }
+
+    #[test]
+    fn test_expand_path_variables() {
+        assert_eq!(
+            expand_path("$HOME/${CACHE}-dir/$", lookup),
+            Ok("/home/floki/cache-dir/$".into())
+        );
+        assert!(expand_path("$MISSING/foo", lookup).is_err());
+        assert!(expand_path("${HOME", lookup).is_err());
+    }
+
+    fn mount(mount_type: MountType, source: Option<&str>, optional: bool) -> Mount {
+        Mount {
+            source: source.map(Into::into),
+            target: "/target".into(),
+            readonly: false,
+            optional,
+            mount_type,
+        }
+    }
+
+    #[test]
+    fn test_resolve_mounts_skips_missing_optional() -> Result<(), Error> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let mounts = vec![
+            mount(MountType::Bind, Some("missing"), true),
+            mount(MountType::Bind, Some("."), false),
+        ];
+        let resolved = resolve_mounts(&mounts, tmp_dir.path())?;
+        assert_eq!(resolved.len(), 1);
+        assert_eq!(
+            resolved[0].source,
+            Some(tmp_dir.path().join(".").display().to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_mounts_rejects_missing_source() {
+        let mounts = vec![mount(MountType::Bind, Some("/does/not/exist"), false)];
+        assert!(resolve_mounts(&mounts, path::Path::new("/")).is_err());
+    }
+
+    #[test]
+    fn test_resolve_mounts_rejects_tmpfs_source() {
+        let mounts = vec![mount(MountType::Tmpfs, Some("/tmp"), false)];
+        assert!(resolve_mounts(&mounts, path::Path::new("/")).is_err());
+    }
+
+    #[test]
+    fn test_mount_argument() {
+        let mount = ResolvedMount {
+            mount_type: MountType::Bind,
+            source: Some("/host/a,b".into()),
+            target: "/guest".into(),
+            readonly: true,
+        };
+        assert_eq!(
+            mount.mount_argument(),
+            "type=bind,\"source=/host/a,b\",target=/guest,readonly"
+        );
+    }
+}
@@ -0,0 +1,509 @@
+/// Organisation and user policies restricting what floki configurations
+/// may do, checked against the fully resolved configuration
+use crate::config::{ConfigFormat, MountType};
+use crate::errors::FlokiError;
+use crate::spec::FlokiSpec;
+
+use anyhow::Error;
+use glob::Pattern;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// The system-wide policy file, typically managed by an organisation
+pub const SYSTEM_POLICY_FILE: &str = "/etc/floki/policy.yaml";
+
+/// Docker switches which are spelt differently but mean the same thing.
+/// Rules and switches are compared using the name on the right.
+const SWITCH_ALIASES: &[(&str, &str)] = &[
+    ("--net", "--network"),
+    ("-v", "--volume"),
+    ("-e", "--env"),
+    ("-p", "--publish"),
+    ("-u", "--user"),
+    ("-w", "--workdir"),
+];
+
+/// Patterns which deny or allow values. A value is denied if it matches
+/// any `deny` pattern, or if there are `allow` patterns and it matches
+/// none of them.
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Rules {
+    #[serde(default)]
+    pub(crate) deny: Vec<String>,
+    #[serde(default)]
+    pub(crate) allow: Vec<String>,
+}
+
+/// The restrictions in a policy file
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Policy {
+    /// Docker switches, such as `--privileged` or `--network=host`. A rule
+    /// without a value matches the switch with any value, and values may
+    /// be glob patterns.
+    #[serde(default)]
+    pub(crate) docker_switches: Rules,
+    /// Glob patterns for host paths bind mounted into containers
+    #[serde(default)]
+    pub(crate) mounts: Rules,
+    /// Glob patterns for the images containers are run from
+    #[serde(default)]
+    pub(crate) images: Rules,
+    /// Whether docker-in-docker may be used
+    #[serde(default)]
+    pub(crate) dind: Option<bool>,
+}
+
+/// What a resolved configuration would do, as far as policies are
+/// concerned
+#[derive(Debug, Default)]
+struct Usage {
+    /// Docker switches, split into the switch and its value
+    switches: Vec<(String, Option<String>)>,
+    /// Host paths bind mounted into the container
+    mounts: Vec<String>,
+    /// Images containers are run from
+    images: Vec<String>,
+    /// Whether docker-in-docker is used
+    dind: bool,
+}
+
+/// Find the policy files which apply: the system-wide policy, and then the
+/// user's own policy next to their configuration file
+pub(crate) fn find_policy_files(
+    system: &Path,
+    user_config_directory: Option<&Path>,
+) -> Vec<PathBuf> {
+    std::iter::once(system.to_path_buf())
+        .chain(user_config_directory.map(|d| d.join("policy.yaml")))
+        .filter(|f| f.is_file())
+        .collect()
+}
+
+/// The policies from each policy file
+#[derive(Debug, Default)]
+pub(crate) struct Policies {
+    policies: Vec<(PathBuf, Policy)>,
+}
+
+impl Policies {
+    pub(crate) fn load(files: &[PathBuf]) -> Result<Self, FlokiError> {
+        let mut policies = Vec::new();
+        for file in files {
+            let name = file.display().to_string();
+            let content = std::fs::read_to_string(file).map_err(|e| {
+                FlokiError::ProblemOpeningConfigYaml {
+                    name: name.clone(),
+                    error: e,
+                }
+            })?;
+            let policy: Policy = ConfigFormat::Yaml.parse(&content, &name)?;
+            // A pattern which can't be parsed would never match, silently
+            // letting through whatever it was meant to deny.
+            policy
+                .check_patterns()
+                .map_err(|reason| FlokiError::InvalidPolicy {
+                    policy: file.clone(),
+                    reason,
+                })?;
+            policies.push((file.clone(), policy));
+        }
+        Ok(Policies { policies })
+    }
+
+    /// Check a resolved configuration against every policy
+    pub(crate) fn check(&self, spec: &FlokiSpec) -> Result<(), Error> {
+        if self.policies.is_empty() {
+            return Ok(());
+        }
+        let mut images = vec![spec.image.name()?];
+        images.extend(spec.dind.iter().map(|dind| dind.image.clone()));
+
+        let mut mounts = vec![spec.paths.root.display().to_string()];
+        for mount in &spec.mounts {
+            if let (MountType::Bind, Some(source)) = (mount.mount_type, &mount.source) {
+                mounts.push(source.clone());
+            }
+        }
+        let switches = split_switches(&spec.docker_switches);
+        mounts.extend(switch_mounts(&switches));
+
+        self.check_usage(&Usage {
+            switches,
+            mounts: mounts.iter().map(|m| canonical(m)).collect(),
+            images,
+            dind: spec.dind.is_some(),
+        })
+    }
+
+    /// Check an image floki runs other than in the configured container,
+    /// such as to change the owner of volumes
+    pub(crate) fn check_image(&self, image: &str) -> Result<(), Error> {
+        self.check_usage(&Usage {
+            images: vec![image.to_string()],
+            ..Default::default()
+        })
+    }
+
+    fn check_usage(&self, usage: &Usage) -> Result<(), Error> {
+        for (file, policy) in &self.policies {
+            policy
+                .check(usage)
+                .map_err(|(rule, reason)| FlokiError::PolicyViolation {
+                    policy: file.clone(),
+                    rule,
+                    reason,
+                })?;
+        }
+        Ok(())
+    }
+}
+
+impl Policy {
+    fn check_patterns(&self) -> Result<(), String> {
+        let rules = [
+            ("docker_switches", &self.docker_switches),
+            ("mounts", &self.mounts),
+            ("images", &self.images),
+        ];
+        for (key, rules) in rules {
+            for (list, patterns) in [("deny", &rules.deny), ("allow", &rules.allow)] {
+                for (i, rule) in patterns.iter().enumerate() {
+                    // Only the values of docker switches are patterns.
+                    let pattern = match key {
+                        "docker_switches" => match rule.split_once('=') {
+                            Some((_, value)) => value,
+                            None => continue,
+                        },
+                        _ => rule,
+                    };
+                    Pattern::new(pattern).map_err(|e| {
+                        format!("invalid pattern `{pattern}` in {key}.{list}[{i}]: {e}")
+                    })?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check what a configuration does, returning the rule which was
+    /// broken and why
+    fn check(&self, usage: &Usage) -> Result<(), (String, String)> {
+        if self.dind == Some(false) && usage.dind {
+            return Err(("dind".into(), "docker-in-docker is not allowed".into()));
+        }
+        for (name, value) in &usage.switches {
+            let shown = match value {
+                Some(value) => format!("{name}={value}"),
+                None => name.clone(),
+            };
+            self.docker_switches.check(
+                "docker_switches",
+                &format!("docker switch `{shown}`"),
+                |rule| switch_matches(rule, name, value.as_deref()),
+            )?;
+        }
+        for mount in &usage.mounts {
+            self.mounts
+                .check("mounts", &format!("mount of '{mount}'"), |rule| {
+                    glob_matches(rule, mount)
+                })?;
+        }
+        for image in &usage.images {
+            self.images
+                .check("images", &format!("image '{image}'"), |rule| {
+                    glob_matches(rule, image)
+                })?;
+        }
+        Ok(())
+    }
+}
+
+impl Rules {
+    fn check<F>(&self, key: &str, what: &str, matches: F) -> Result<(), (String, String)>
+    where
+        F: Fn(&str) -> bool,
+    {
+        if let Some((i, rule)) = self.deny.iter().enumerate().find(|(_, r)| matches(r)) {
+            return Err((
+                format!("{key}.deny[{i}] `{rule}`"),
+                format!("{what} is denied"),
+            ));
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|r| matches(r)) {
+            return Err((format!("{key}.allow"), format!("{what} is not allowed")));
+        }
+        Ok(())
+    }
+}
+
+fn glob_matches(rule: &str, value: &str) -> bool {
+    Pattern::new(rule).is_ok_and(|pattern| pattern.matches(value))
+}
+
+/// Whether a switch rule, such as `--privileged` or `--network=host`,
+/// matches a switch
+fn switch_matches(rule: &str, name: &str, value: Option<&str>) -> bool {
+    let (rule_name, rule_value) = match rule.split_once('=') {
+        Some((rule_name, rule_value)) => (rule_name, Some(rule_value)),
+        None => (rule, None),
+    };
+    if canonical_switch(rule_name) != name {
+        return false;
+    }
+    match (rule_value, value) {
+        (None, _) => true,
+        (Some(rule_value), Some(value)) => glob_matches(rule_value, value),
+        (Some(_), None) => false,
+    }
+}
+
+fn canonical_switch(name: &str) -> &str {
+    SWITCH_ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == name)
+        .map(|(_, canonical)| *canonical)
+        .unwrap_or(name)
+}
+
+/// Split a short switch from a value attached to it, e.g. `-v/:/host`
+fn split_short_switch(arg: &str) -> Option<(&str, &str)> {
+    if arg.starts_with("--") || arg.len() <= 2 || !arg.is_char_boundary(2) {
+        return None;
+    }
+    let (name, value) = arg.split_at(2);
+    SWITCH_ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == name)
+        .map(|(_, canonical)| (*canonical, value.strip_prefix('=').unwrap_or(value)))
+}
+
+/// Split docker switches into each switch and its value, which is either
+/// attached to a short switch, given after `=` or as the following argument
+fn split_switches(switches: &[String]) -> Vec<(String, Option<String>)> {
+    let mut split = Vec::new();
+    let mut args = switches.iter().peekable();
+    while let Some(arg) = args.next() {
+        if let Some((name, value)) = split_short_switch(arg) {
+            split.push((name.to_string(), Some(value.to_string())));
+        } else if let Some((name, value)) = arg.split_once('=').filter(|_| arg.starts_with('-')) {
+            split.push((canonical_switch(name).to_string(), Some(value.to_string())));
+        } else if arg.starts_with('-') {
+            let value = args.next_if(|next| !next.starts_with('-')).cloned();
+            split.push((canonical_switch(arg).to_string(), value));
+        }
+    }
+    split
+}
+
+/// The host paths bind mounted by `--volume` and `--mount` switches
+fn switch_mounts(switches: &[(String, Option<String>)]) -> Vec<String> {
+    let mut mounts = Vec::new();
+    for (name, value) in switches {
+        let value = match value {
+            Some(value) => value,
+            None => continue,
+        };
+        match name.as_str() {
+            "--volume" => {
+                let source = value.split(':').next().unwrap_or_default();
+                // Anything else is the name of a docker volume.
+                if source.starts_with('/') || source.starts_with('.') || source.starts_with('~') {
+                    mounts.push(source.to_string());
+                }
+            }
+            "--mount" => {
+                let fields = value
+                    .split(',')
+                    .filter_map(|f| f.split_once('='))
+                    .collect::<Vec<_>>();
+                if fields.contains(&("type", "bind")) {
+                    mounts.extend(
+                        fields
+                            .iter()
+                            .filter(|(k, _)| *k == "source" || *k == "src")
+                            .map(|(_, v)| v.to_string()),
+                    );
+                }
+            }
+            _ => (),
+        }
+    }
+    mounts
+}
+
+/// Resolve a host path, so that e.g. `/etc/..` is recognised as `/`
+fn canonical(path: &str) -> String {
+    std::fs::canonicalize(path)
+        .map(|p| p.display().to_string())
+        .unwrap_or_else(|_| path.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn usage(switches: &[&str]) -> Usage {
+        let switches = split_switches(&switches.iter().map(|s| s.to_string()).collect::<Vec<_>>());
+        let mounts = switch_mounts(&switches)
+            .iter()
+            .map(|m| canonical(m))
+            .collect();
+        Usage {
+            switches,
+            mounts,
+            images: vec!["registry.example.com/build:1".into()],
+            dind: false,
+        }
+    }
+
+    fn rule(policy: &Policy, switches: &[&str]) -> Option<String> {
+        policy.check(&usage(switches)).err().map(|(rule, _)| rule)
+    }
+
+    #[test]
+    fn test_split_switches() {
+        let switches = [
+            "--privileged",
+            "--net",
+            "host",
+            "-e",
+            "A=1",
+            "--rm",
+            "--cpus=2",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect::<Vec<_>>();
+        let some = |s: &str| Some(s.to_string());
+        assert_eq!(
+            split_switches(&switches),
+            vec![
+                ("--privileged".to_string(), None),
+                ("--network".to_string(), some("host")),
+                ("--env".to_string(), some("A=1")),
+                ("--rm".to_string(), None),
+                ("--cpus".to_string(), some("2")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_policy_denies() {
+        let policy: Policy = ConfigFormat::Yaml
+            .parse(
+                r#"
+docker_switches:
+  deny: [--privileged, --net=host, --cap-add=SYS_*]
+mounts:
+  deny: ["/", "/etc/**"]
+"#,
+                "policy.yaml",
+            )
+            .unwrap();
+
+        assert_eq!(rule(&policy, &["--rm", "--network=bridge"]), None);
+        assert_eq!(
+            rule(&policy, &["--privileged=true"]),
+            Some("docker_switches.deny[0] `--privileged`".into())
+        );
+        assert_eq!(
+            rule(&policy, &["--network", "host"]),
+            Some("docker_switches.deny[1] `--net=host`".into())
+        );
+        assert_eq!(
+            rule(&policy, &["--cap-add", "SYS_ADMIN"]),
+            Some("docker_switches.deny[2] `--cap-add=SYS_*`".into())
+        );
+        assert_eq!(
+            rule(&policy, &["-v", "/etc/..:/host"]),
+            Some("mounts.deny[0] `/`".into())
+        );
+        assert_eq!(
+            rule(&policy, &["--mount", "type=bind,src=/etc/passwd,dst=/p"]),
+            Some("mounts.deny[1] `/etc/**`".into())
+        );
+        assert_eq!(rule(&policy, &["-v", "cache:/cache"]), None);
+    }
+
+    #[test]
+    fn test_policy_denies_attached_short_switch_values() {
+        let policy: Policy = ConfigFormat::Yaml
+            .parse(
+                "docker_switches:\n  deny: [--user=root]\nmounts:\n  deny: [\"/\"]",
+                "policy.yaml",
+            )
+            .unwrap();
+
+        assert_eq!(
+            rule(&policy, &["-v/:/host"]),
+            Some("mounts.deny[0] `/`".into())
+        );
+        assert_eq!(
+            rule(&policy, &["-v=/:/host"]),
+            Some("mounts.deny[0] `/`".into())
+        );
+        assert_eq!(
+            rule(&policy, &["-uroot"]),
+            Some("docker_switches.deny[0] `--user=root`".into())
+        );
+        assert_eq!(rule(&policy, &["-vcache:/cache", "--rm"]), None);
+    }
+
+    #[test]
+    fn test_invalid_patterns_are_rejected() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let policy_file = tmp_dir.path().join("policy.yaml");
+        std::fs::write(&policy_file, "mounts:\n  deny: [\"/etc/[\"]\n")?;
+
+        match Policies::load(&[policy_file]) {
+            Err(FlokiError::InvalidPolicy { reason, .. }) => {
+                assert!(reason.contains("`/etc/[` in mounts.deny[0]"), "{}", reason)
+            }
+            other => panic!("expected an invalid policy, got {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_image() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let policy_file = tmp_dir.path().join("policy.yaml");
+        std::fs::write(&policy_file, "images:\n  allow: [\"debian:*\"]\n")?;
+        let policies = Policies::load(&[policy_file])?;
+
+        policies.check_image("debian:12")?;
+        let error = policies.check_image("alpine").unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<FlokiError>(),
+            Some(FlokiError::PolicyViolation { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_policy_allows() {
+        let policy = Policy {
+            images: Rules {
+                allow: vec!["registry.example.com/*".into()],
+                ..Default::default()
+            },
+            dind: Some(false),
+            ..Default::default()
+        };
+        assert_eq!(rule(&policy, &[]), None);
+
+        let mut usage = usage(&[]);
+        usage.images.push("docker:dind".into());
+        assert_eq!(
+            policy.check(&usage),
+            Err((
+                "images.allow".into(),
+                "image 'docker:dind' is not allowed".into()
+            ))
+        );
+        usage.dind = true;
+        assert_eq!(policy.check(&usage).unwrap_err().0, "dind");
+    }
+}
@@ -0,0 +1,123 @@
+/// Confinement of the files templates can read to the project, so that
+/// rendering a configuration from an untrusted clone can't read arbitrary
+/// files on the host
+use crate::errors::FlokiError;
+
+use std::path::{Component, Path, PathBuf};
+
+/// The directories templates are allowed to read files from
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Sandbox {
+    allowed: Vec<PathBuf>,
+}
+
+impl Sandbox {
+    /// Allow access to the given directories and everything beneath them
+    pub(crate) fn new<I>(directories: I) -> Self
+    where
+        I: IntoIterator<Item = PathBuf>,
+    {
+        let mut allowed = Vec::new();
+        for directory in directories {
+            if directory.as_os_str().is_empty() {
+                continue;
+            }
+            if let Some(resolved) = resolve(&directory) {
+                if !allowed.contains(&resolved) {
+                    allowed.push(resolved);
+                }
+            }
+        }
+        debug!("Templates may read files in {allowed:?}");
+        Sandbox { allowed }
+    }
+
+    /// Whether templates may read a path. Symlinks are followed, so a link
+    /// inside an allowed directory can't point outside of it.
+    pub(crate) fn allows(&self, path: &Path) -> bool {
+        resolve(path).is_some_and(|resolved| self.allowed.iter().any(|a| resolved.starts_with(a)))
+    }
+
+    /// Check that templates may read a path
+    pub(crate) fn check(&self, path: &Path) -> Result<(), FlokiError> {
+        if self.allows(path) {
+            Ok(())
+        } else {
+            Err(FlokiError::TemplateAccessDenied {
+                path: path.to_path_buf(),
+                allowed: self.allowed.clone(),
+            })
+        }
+    }
+
+    /// Check that templates may read a path, from within a template function
+    pub(crate) fn guard(&self, path: &Path) -> tera::Result<()> {
+        self.check(path)
+            .map_err(|e| tera::Error::chain("Template file access denied", e))
+    }
+}
+
+/// Find the access denial which caused a template to fail, if any, so it
+/// can be reported directly rather than buried in the rendering error
+pub(crate) fn access_denied(error: &tera::Error) -> Option<FlokiError> {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(error);
+    while let Some(error) = source {
+        if let Some(FlokiError::TemplateAccessDenied { path, allowed }) =
+            error.downcast_ref::<FlokiError>()
+        {
+            return Some(FlokiError::TemplateAccessDenied {
+                path: path.clone(),
+                allowed: allowed.clone(),
+            });
+        }
+        source = error.source();
+    }
+    None
+}
+
+/// Resolve a path to its canonical form. Paths which don't exist are
+/// resolved as far as they do, and can't be resolved at all if the rest
+/// of the path steps back up with `..`.
+fn resolve(path: &Path) -> Option<PathBuf> {
+    if let Ok(canonical) = path.canonicalize() {
+        return Some(canonical);
+    }
+    match path.components().next_back()? {
+        Component::Normal(name) => Some(resolve(path.parent()?)?.join(name)),
+        Component::CurDir => resolve(path.parent()?),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sandbox() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let project = tmp_dir.path().join("project");
+        let outside = tmp_dir.path().join("outside");
+        std::fs::create_dir_all(project.join("config"))?;
+        std::fs::create_dir_all(&outside)?;
+        std::fs::write(outside.join("secret"), "secret")?;
+        std::os::unix::fs::symlink(&outside, project.join("link"))?;
+
+        let sandbox = Sandbox::new(vec![project.clone(), PathBuf::new()]);
+        assert!(sandbox.allows(&project.join("config/values.yaml")));
+        assert!(sandbox.allows(&project.join("config/missing/./values.yaml")));
+        assert!(sandbox.allows(&project.join("config/../values.yaml")));
+        assert!(!sandbox.allows(&project.join("config/../../outside/secret")));
+        assert!(!sandbox.allows(&project.join("missing/../../outside/secret")));
+        assert!(!sandbox.allows(&project.join("link/secret")));
+        assert!(!sandbox.allows(Path::new("/etc/passwd")));
+
+        let error =
+            tera::Error::call_function("yaml", sandbox.guard(&outside.join("secret")).unwrap_err());
+        assert!(matches!(
+            access_denied(&error),
+            Some(FlokiError::TemplateAccessDenied { .. })
+        ));
+        Ok(())
+    }
+}
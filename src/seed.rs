@@ -0,0 +1,241 @@
+/// Seeding of newly created floki volumes with initial content
+use crate::config::Seed;
+use crate::errors::{FlokiError, FlokiSubprocessExitStatus};
+use crate::mounts::expand_path;
+use crate::volumes::{ResolvedVolume, VolumeSource};
+
+use anyhow::Error;
+use std::ffi::OsStr;
+use std::fs;
+use std::path;
+use std::process::{Command, Stdio};
+
+/// Apply the seed configured for a volume which has just been created
+pub(crate) fn seed_volume(
+    volume: &ResolvedVolume,
+    image: &str,
+    floki_root: &path::Path,
+) -> Result<(), Error> {
+    let seed = match &volume.config.seed {
+        Some(seed) => seed,
+        None => return Ok(()),
+    };
+    info!("Seeding volume '{}'", volume.name);
+
+    let failed = |reason: String| FlokiError::FailedToSeedVolume {
+        name: volume.name.to_string(),
+        reason,
+    };
+    let host_path = |p: &path::Path| -> Result<path::PathBuf, Error> {
+        let expanded =
+            expand_path(&p.to_string_lossy(), |var| std::env::var(var).ok()).map_err(&failed)?;
+        let full_path = floki_root.join(expanded);
+        if full_path.exists() {
+            Ok(full_path)
+        } else {
+            Err(failed(format!("'{}' does not exist", full_path.display())).into())
+        }
+    };
+    let mount = &volume.config.mount;
+
+    match (seed, &volume.source) {
+        (Seed::Image { image: false }, _) => Ok(()),
+        // Docker itself copies the image's content into an empty named
+        // volume the first time it is mounted.
+        (Seed::Image { image: true }, VolumeSource::Docker(_)) => Ok(()),
+        (Seed::Image { image: true }, VolumeSource::Directory(dir)) => {
+            let container = DockerContainer::create(image, &[])?;
+            let src = format!("{}:{}", container.name, mount.join(".").display());
+            container.copy(&[OsStr::new(&src), dir.as_os_str()])
+        }
+        (Seed::Directory { directory }, VolumeSource::Directory(dir)) => {
+            copy_directory(&host_path(directory)?, dir)
+        }
+        (Seed::Tarball { tarball }, VolumeSource::Directory(dir)) => {
+            let exit_status = Command::new("tar")
+                .arg("-xf")
+                .arg(host_path(tarball)?)
+                .arg("-C")
+                .arg(dir)
+                .stdin(Stdio::null())
+                .status()?;
+            if exit_status.success() {
+                Ok(())
+            } else {
+                Err(failed(format!("tar failed with {exit_status}")).into())
+            }
+        }
+        (Seed::Directory { directory }, VolumeSource::Docker(name)) => {
+            let container = DockerContainer::create(image, &volume_switch(name, mount))?;
+            let src = host_path(directory)?.join(".");
+            let dst = format!("{}:{}", container.name, mount.display());
+            container.copy(&[src.as_os_str(), OsStr::new(&dst)])
+        }
+        (Seed::Tarball { tarball }, VolumeSource::Docker(name)) => {
+            let container = DockerContainer::create(image, &volume_switch(name, mount))?;
+            let dst = format!("{}:{}", container.name, mount.display());
+            container.copy_from_stdin(fs::File::open(host_path(tarball)?)?, &dst)
+        }
+    }
+}
+
+fn volume_switch(name: &str, mount: &path::Path) -> Vec<String> {
+    vec!["-v".into(), format!("{name}:{}", mount.display())]
+}
+
+/// A stopped container used to copy content in or out of volumes,
+/// which is removed when dropped
+struct DockerContainer {
+    name: String,
+}
+
+impl DockerContainer {
+    fn create(image: &str, switches: &[String]) -> Result<Self, Error> {
+        let name = uuid::Uuid::new_v4().to_string();
+        // The command is never run, but docker requires one for
+        // images which don't specify one.
+        run_docker(
+            Command::new("docker")
+                .args(["create", "--name", &name])
+                .args(switches)
+                .args([image, "true"])
+                .stdin(Stdio::null())
+                .stdout(Stdio::null()),
+            "docker create",
+        )?;
+        Ok(DockerContainer { name })
+    }
+
+    fn copy(&self, args: &[&OsStr]) -> Result<(), Error> {
+        run_docker(
+            Command::new("docker")
+                .arg("cp")
+                .args(args)
+                .stdin(Stdio::null())
+                .stdout(Stdio::null()),
+            "docker cp",
+        )
+    }
+
+    fn copy_from_stdin(&self, archive: fs::File, dst: &str) -> Result<(), Error> {
+        run_docker(
+            Command::new("docker")
+                .args(["cp", "-", dst])
+                .stdin(archive)
+                .stdout(Stdio::null()),
+            "docker cp",
+        )
+    }
+}
+
+impl Drop for DockerContainer {
+    fn drop(&mut self) {
+        let _ = Command::new("docker")
+            .args(["rm", &self.name])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+    }
+}
+
+fn run_docker(command: &mut Command, description: &str) -> Result<(), Error> {
+    let exit_status = command
+        .status()
+        .map_err(|e| FlokiError::FailedToLaunchDocker { error: e })?;
+    if exit_status.success() {
+        Ok(())
+    } else {
+        Err(FlokiError::RunContainerFailed {
+            exit_status: FlokiSubprocessExitStatus {
+                process_description: description.into(),
+                exit_status,
+            },
+        }
+        .into())
+    }
+}
+
+/// Recursively copy the content of one directory into another,
+/// recreating symlinks rather than following them
+fn copy_directory(src: &path::Path, dst: &path::Path) -> Result<(), Error> {
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let target = dst.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            fs::create_dir_all(&target)?;
+            copy_directory(&entry.path(), &target)?;
+        } else if file_type.is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(entry.path())?, &target)?;
+        } else {
+            fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Volume;
+
+    #[test]
+    fn test_copy_directory() -> Result<(), Error> {
+        let src = tempfile::TempDir::new()?;
+        let dst = tempfile::TempDir::new()?;
+        fs::create_dir_all(src.path().join("sub"))?;
+        fs::write(src.path().join("sub/file"), "content")?;
+        std::os::unix::fs::symlink("sub/file", src.path().join("link"))?;
+
+        copy_directory(src.path(), dst.path())?;
+
+        assert_eq!(fs::read_to_string(dst.path().join("sub/file"))?, "content");
+        assert_eq!(
+            fs::read_link(dst.path().join("link"))?,
+            path::Path::new("sub/file")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_seed_volume_from_directory() -> Result<(), Error> {
+        let root = tempfile::TempDir::new()?;
+        let dst = tempfile::TempDir::new()?;
+        fs::create_dir_all(root.path().join("seed"))?;
+        fs::write(root.path().join("seed/file"), "content")?;
+        let config = Volume {
+            seed: Some(Seed::Directory {
+                directory: "seed".into(),
+            }),
+            ..Default::default()
+        };
+        let volume = ResolvedVolume {
+            name: "cache",
+            config: &config,
+            source: VolumeSource::Directory(dst.path().to_path_buf()),
+        };
+
+        seed_volume(&volume, "image", root.path())?;
+
+        assert_eq!(fs::read_to_string(dst.path().join("file"))?, "content");
+        Ok(())
+    }
+
+    #[test]
+    fn test_seed_volume_missing_source() {
+        let config = Volume {
+            seed: Some(Seed::Tarball {
+                tarball: "missing.tar.gz".into(),
+            }),
+            ..Default::default()
+        };
+        let volume = ResolvedVolume {
+            name: "cache",
+            config: &config,
+            source: VolumeSource::Directory("/nonexistent".into()),
+        };
+
+        assert!(seed_volume(&volume, "image", path::Path::new("/")).is_err());
+    }
+}
@@ -1,7 +1,8 @@
 use crate::config::{DindConfig, FlokiConfig};
 use crate::dind::DEFAULT_DIND_IMAGE;
-use crate::environment::Environment;
+use crate::environment::{Environment, Terminal};
 use crate::errors;
+use crate::mounts::{resolve_mounts, ResolvedMount};
 
 use anyhow::Error;
 
@@ -64,6 +65,8 @@ pub(crate) struct FlokiSpec {
     pub(crate) entrypoint: Option<String>,
     /// Volumes to mount into the container
     pub(crate) volumes: BTreeMap<String, crate::config::Volume>,
+    /// Validated mounts of host paths, tmpfs filesystems and docker volumes
+    pub(crate) mounts: Vec<ResolvedMount>,
     /// User details and forwarding
     pub(crate) user: User,
     /// SSH agent forwarding
@@ -74,10 +77,14 @@ pub(crate) struct FlokiSpec {
     pub(crate) dind: Option<Dind>,
     /// Paths on the host which are relevant to running
     pub(crate) paths: Paths,
+    /// How the container is attached to the terminal
+    pub(crate) terminal: Terminal,
 }
 
 impl FlokiSpec {
-    pub(crate) fn from(config: FlokiConfig, environ: Environment) -> Result<Self, Error> {
+    pub(crate) fn from(config: FlokiConfig, mut environ: Environment) -> Result<Self, Error> {
+        environ.apply_config_root(config.root.as_deref())?;
+
         let dind = match config.dind {
             DindConfig::Toggle(true) => Some(Dind {
                 image: DEFAULT_DIND_IMAGE.to_string(),
@@ -87,14 +94,14 @@ impl FlokiSpec {
         };
 
         let user = User {
-            forward: config.forward_user,
+            forward: config.forward_user.unwrap_or(false),
             uid: environ.user_details.uid,
             gid: environ.user_details.gid,
         };
 
         let entrypoint = config.entrypoint.value().map(|v| v.to_string());
 
-        let ssh_agent = if config.forward_ssh_agent {
+        let ssh_agent = if config.forward_ssh_agent.unwrap_or(false) {
             if let Some(path) = environ.ssh_agent_socket {
                 Ok(Some(SshAgent { path }))
             } else {
@@ -107,8 +114,11 @@ impl FlokiSpec {
         let internal_working_directory = get_working_directory(
             &environ.current_directory,
             &environ.floki_root,
-            &path::PathBuf::from(&config.mount),
-        );
+            &config.mount,
+            config.workdir.as_deref(),
+        )?;
+
+        let mounts = resolve_mounts(&config.mounts, &environ.floki_root)?;
 
         let paths = Paths {
             internal_working_directory,
@@ -126,11 +136,13 @@ impl FlokiSpec {
             shell: config.shell,
             entrypoint,
             volumes: config.volumes,
+            mounts,
             user,
             ssh_agent,
             docker_switches,
             dind,
             paths,
+            terminal: environ.terminal,
         };
 
         debug!("built spec from config and environment: {spec:?}");
@@ -139,7 +151,7 @@ impl FlokiSpec {
     }
 }
 
-fn decompose_switches(specs: &[String]) -> Result<Vec<String>, Error> {
+pub(crate) fn decompose_switches(specs: &[String]) -> Result<Vec<String>, Error> {
     let mut flattened = Vec::new();
 
     for spec in specs {
@@ -155,16 +167,42 @@ fn decompose_switches(specs: &[String]) -> Result<Vec<String>, Error> {
     Ok(flattened)
 }
 
-/// Determine what directory we are currently in
-fn get_working_directory(
+/// Determine the working directory inside the container. This is the
+/// configured `workdir` if there is one, otherwise the directory
+/// corresponding to the current directory on the host.
+pub(crate) fn get_working_directory(
     current_directory: &path::Path,
     floki_root: &path::Path,
     mount: &path::Path,
-) -> path::PathBuf {
-    mount.join(current_directory.strip_prefix(floki_root).expect(
-        "failed to deduce working directory - \
-         floki_root should always be an ancestor of current_directory",
-    ))
+    workdir: Option<&path::Path>,
+) -> Result<path::PathBuf, errors::FlokiError> {
+    if let Some(workdir) = workdir {
+        if workdir.is_relative()
+            && workdir
+                .components()
+                .any(|c| c == path::Component::ParentDir)
+        {
+            return Err(errors::FlokiError::InvalidWorkingDirectory {
+                path: workdir.display().to_string(),
+                reason: "a relative workdir must not leave the mount directory".into(),
+            });
+        }
+        // An absolute workdir replaces the mount path entirely.
+        return Ok(mount.join(workdir));
+    }
+
+    match current_directory.strip_prefix(floki_root) {
+        Ok(relative) => Ok(mount.join(relative)),
+        Err(_) => {
+            warn!(
+                "The current directory '{}' is outside the floki root '{}' - starting in '{}'",
+                current_directory.display(),
+                floki_root.display(),
+                mount.display()
+            );
+            Ok(mount.to_path_buf())
+        }
+    }
 }
 
 #[cfg(test)]
@@ -197,8 +235,39 @@ mod test {
         let mount = path::PathBuf::from("/guest");
 
         assert!(
-            get_working_directory(&current_directory, &floki_root, &mount)
+            get_working_directory(&current_directory, &floki_root, &mount, None).unwrap()
                 == path::Path::new("/guest/workingdir/")
         )
     }
+
+    #[test]
+    fn test_get_working_directory_outside_root() {
+        let current_directory = path::PathBuf::from("/elsewhere/workingdir/");
+        let floki_root = path::PathBuf::from("/host");
+        let mount = path::PathBuf::from("/guest");
+
+        assert_eq!(
+            get_working_directory(&current_directory, &floki_root, &mount, None).unwrap(),
+            path::Path::new("/guest")
+        )
+    }
+
+    #[test]
+    fn test_get_working_directory_from_config() {
+        let current_directory = path::PathBuf::from("/host/workingdir/");
+        let floki_root = path::PathBuf::from("/host");
+        let mount = path::PathBuf::from("/guest");
+        let get = |workdir: &str| {
+            get_working_directory(
+                &current_directory,
+                &floki_root,
+                &mount,
+                Some(path::Path::new(workdir)),
+            )
+        };
+
+        assert_eq!(get("build").unwrap(), path::Path::new("/guest/build"));
+        assert_eq!(get("/opt/build").unwrap(), path::Path::new("/opt/build"));
+        assert!(get("../build").is_err());
+    }
 }
@@ -0,0 +1,222 @@
+/// Loading of the templates a configuration file includes, imports or
+/// extends
+use crate::errors::FlokiError;
+use crate::sandbox::Sandbox;
+
+use std::path::{Path, PathBuf};
+use tera::ast::Node;
+use tera::Template;
+
+/// Find every template referenced by a template, directly or through
+/// other templates, returning their names and content. Names are looked up
+/// in each of the directories in turn.
+pub(crate) fn referenced_templates(
+    name: &str,
+    content: &str,
+    directories: &[PathBuf],
+    sandbox: &Sandbox,
+) -> Result<Vec<(String, String)>, FlokiError> {
+    let mut loader = Loader {
+        directories,
+        sandbox,
+        stack: Vec::new(),
+        loaded: Vec::new(),
+    };
+    loader.visit(name, content)?;
+    Ok(loader.loaded)
+}
+
+struct Loader<'a> {
+    directories: &'a [PathBuf],
+    sandbox: &'a Sandbox,
+    /// The templates being visited, for detecting cycles
+    stack: Vec<String>,
+    /// The templates found so far
+    loaded: Vec<(String, String)>,
+}
+
+impl Loader<'_> {
+    fn visit(&mut self, name: &str, content: &str) -> Result<(), FlokiError> {
+        let template = Template::new(name, None, content).map_err(|e| {
+            FlokiError::ProblemRenderingTemplate {
+                name: name.to_string(),
+                error: e,
+            }
+        })?;
+        self.stack.push(name.to_string());
+        for (candidates, optional) in references(&template) {
+            self.load_reference(name, &candidates, optional)?;
+        }
+        self.stack.pop();
+        Ok(())
+    }
+
+    /// Load the first of the candidate templates which can be found
+    fn load_reference(
+        &mut self,
+        parent: &str,
+        candidates: &[String],
+        optional: bool,
+    ) -> Result<(), FlokiError> {
+        for candidate in candidates {
+            if let Some(position) = self.stack.iter().position(|n| n == candidate) {
+                let mut cycle = self.stack[position..].to_vec();
+                cycle.push(candidate.clone());
+                return Err(FlokiError::TemplateIncludeCycle { cycle });
+            }
+            if self.loaded.iter().any(|(n, _)| n == candidate) {
+                return Ok(());
+            }
+            if let Some(path) = self.find(candidate) {
+                self.sandbox.check(&path)?;
+                let content = std::fs::read_to_string(&path).map_err(|e| {
+                    FlokiError::ProblemOpeningConfigYaml {
+                        name: path.display().to_string(),
+                        error: e,
+                    }
+                })?;
+                self.loaded.push((candidate.clone(), content.clone()));
+                return self.visit(candidate, &content);
+            }
+        }
+        if optional {
+            return Ok(());
+        }
+        Err(FlokiError::TemplateNotFound {
+            name: candidates.join(", "),
+            parent: parent.to_string(),
+            searched: self.directories.to_vec(),
+        })
+    }
+
+    fn find(&self, name: &str) -> Option<PathBuf> {
+        self.directories
+            .iter()
+            .map(|directory| directory.join(Path::new(name)))
+            .find(|path| path.is_file())
+    }
+}
+
+/// The templates a template refers to, each given as the candidate names
+/// and whether it may be missing
+fn references(template: &Template) -> Vec<(Vec<String>, bool)> {
+    let mut references = Vec::new();
+    if let Some(parent) = &template.parent {
+        references.push((vec![parent.clone()], false));
+    }
+    for (file, _) in &template.imported_macro_files {
+        references.push((vec![file.clone()], false));
+    }
+    collect_includes(&template.ast, &mut references);
+    for definition in template.macros.values() {
+        collect_includes(&definition.body, &mut references);
+    }
+    references
+}
+
+fn collect_includes(nodes: &[Node], references: &mut Vec<(Vec<String>, bool)>) {
+    for node in nodes {
+        match node {
+            Node::Include(_, names, ignore_missing) => {
+                references.push((names.clone(), *ignore_missing))
+            }
+            Node::MacroDefinition(_, definition, _) => {
+                collect_includes(&definition.body, references)
+            }
+            Node::FilterSection(_, section, _) => collect_includes(&section.body, references),
+            Node::Block(_, block, _) => collect_includes(&block.body, references),
+            Node::Forloop(_, forloop, _) => {
+                collect_includes(&forloop.body, references);
+                if let Some(body) = &forloop.empty_body {
+                    collect_includes(body, references);
+                }
+            }
+            Node::If(condition, _) => {
+                for (_, _, body) in &condition.conditions {
+                    collect_includes(body, references);
+                }
+                if let Some((_, body)) = &condition.otherwise {
+                    collect_includes(body, references);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn names(templates: &[(String, String)]) -> Vec<&str> {
+        templates.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_referenced_templates() -> Result<(), Box<dyn std::error::Error>> {
+        let project = tempfile::TempDir::new()?;
+        let shared = tempfile::TempDir::new()?;
+        std::fs::create_dir(project.path().join("fragments"))?;
+        std::fs::write(
+            project.path().join("fragments/init.yaml"),
+            "{% if true %}{% include \"fragments/common.yaml\" %}{% endif %}",
+        )?;
+        std::fs::write(project.path().join("fragments/common.yaml"), "- echo hi")?;
+        std::fs::write(
+            shared.path().join("common.floki.j2"),
+            "{% macro image(name) %}{{ name }}{% endmacro %}",
+        )?;
+
+        let template = r#"{% import "common.floki.j2" as common %}
+{% include "fragments/init.yaml" %}
+{% include ["missing.yaml", "fragments/common.yaml"] %}
+{% include "optional.yaml" ignore missing %}"#;
+        let directories = [project.path().to_path_buf(), shared.path().to_path_buf()];
+        let templates = referenced_templates(
+            "floki.yaml",
+            template,
+            &directories,
+            &Sandbox::new(directories.to_vec()),
+        )?;
+        assert_eq!(
+            names(&templates),
+            vec![
+                "common.floki.j2",
+                "fragments/init.yaml",
+                "fragments/common.yaml"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_referenced_template_errors() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let project = tmp_dir.path().join("project");
+        std::fs::create_dir(&project)?;
+        std::fs::write(project.join("a.j2"), r#"{% include "b.j2" %}"#)?;
+        std::fs::write(project.join("b.j2"), r#"{% include "a.j2" %}"#)?;
+        std::fs::write(tmp_dir.path().join("outside.j2"), "secret")?;
+        let directories = [project.clone()];
+        let sandbox = Sandbox::new(directories.to_vec());
+        let find = |template| referenced_templates("floki.yaml", template, &directories, &sandbox);
+
+        match find(r#"{% include "a.j2" %}"#) {
+            Err(FlokiError::TemplateIncludeCycle { cycle }) => {
+                assert_eq!(cycle, vec!["a.j2", "b.j2", "a.j2"])
+            }
+            other => panic!("expected an include cycle, got {:?}", other),
+        }
+        match find(r#"{% include "c.j2" %}"#) {
+            Err(FlokiError::TemplateNotFound { name, parent, .. }) => {
+                assert_eq!((name.as_str(), parent.as_str()), ("c.j2", "floki.yaml"))
+            }
+            other => panic!("expected a missing template, got {:?}", other),
+        }
+        match find(r#"{% include "../outside.j2" %}"#) {
+            Err(FlokiError::TemplateAccessDenied { .. }) => (),
+            other => panic!("expected access to be denied, got {:?}", other),
+        }
+        Ok(())
+    }
+}
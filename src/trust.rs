@@ -0,0 +1,339 @@
+/// Recording which configuration files the user trusts to run commands on
+/// the host or privileged containers, so that running floki in an
+/// untrusted clone can't do so without asking
+use crate::config::{DindConfig, FlokiConfig, MountType, Seed};
+use crate::errors::FlokiError;
+use crate::files::sha256_hex;
+use crate::mounts::expand_path;
+use crate::volumes::hash_path;
+
+use anyhow::Error;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Directory in the floki workspace holding a record for each trusted
+/// configuration file
+static TRUST_DIRECTORY: &str = "trust/";
+
+/// Whether a configuration file has been trusted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TrustStatus {
+    /// Trusted, and unchanged since
+    Trusted,
+    /// Never trusted
+    Unknown,
+    /// Trusted, but the rendered configuration has changed since
+    Changed,
+}
+
+/// The trusted configuration files, recorded in the floki workspace
+#[derive(Debug)]
+pub(crate) struct TrustStore {
+    directory: PathBuf,
+}
+
+impl TrustStore {
+    pub(crate) fn new(workspace: &Path) -> Self {
+        TrustStore {
+            directory: workspace.join(TRUST_DIRECTORY),
+        }
+    }
+
+    /// The record for a configuration file holds the hash of the rendered
+    /// configuration which was trusted, followed by the file's path
+    fn record(&self, config_file: &Path) -> PathBuf {
+        self.directory.join(hash_path(config_file))
+    }
+
+    pub(crate) fn status(
+        &self,
+        config_file: &Path,
+        config: &FlokiConfig,
+    ) -> Result<TrustStatus, Error> {
+        let hash = config_hash(config)?;
+        Ok(match std::fs::read_to_string(self.record(config_file)) {
+            Ok(record) if record.lines().next() == Some(&hash) => TrustStatus::Trusted,
+            Ok(_) => TrustStatus::Changed,
+            Err(_) => TrustStatus::Unknown,
+        })
+    }
+
+    /// Trust the rendered configuration from a file
+    pub(crate) fn trust(&self, config_file: &Path, config: &FlokiConfig) -> Result<(), Error> {
+        let hash = config_hash(config)?;
+        std::fs::create_dir_all(&self.directory)?;
+        std::fs::write(
+            self.record(config_file),
+            format!("{}\n{}\n", hash, config_file.display()),
+        )?;
+        Ok(())
+    }
+
+    /// Stop trusting a configuration file, returning whether it was trusted
+    pub(crate) fn untrust(&self, config_file: &Path) -> Result<bool, Error> {
+        match std::fs::remove_file(self.record(config_file)) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+fn config_hash(config: &FlokiConfig) -> Result<String, Error> {
+    let serialized = serde_json::to_string(config)?;
+    Ok(sha256_hex(serialized.as_bytes()))
+}
+
+/// Describe what a configuration file does which needs the user's trust
+pub(crate) fn risks(config_file: &Path, config: &FlokiConfig) -> Vec<String> {
+    let mut risks = Vec::new();
+    let directory = config_file.parent().unwrap_or_else(|| Path::new(""));
+    let floki_root = directory.join(config.root.as_deref().unwrap_or_else(|| Path::new("")));
+    if let Some(root) = &config.root {
+        if !is_within(directory, &floki_root) {
+            risks.push(format!(
+                "mount '{}' from the host as the floki root",
+                root.display()
+            ));
+        }
+    }
+    if let Some(command) = config.image.host_command() {
+        risks.push(format!("run `{command}` on the host to obtain the image"));
+    }
+    if !matches!(config.dind, DindConfig::Toggle(false)) {
+        risks.push("run a privileged docker-in-docker container".to_string());
+    }
+    if !config.docker_switches.is_empty() {
+        risks.push(format!(
+            "pass `{}` to docker",
+            config.docker_switches.join(" ")
+        ));
+    }
+    for mount in &config.mounts {
+        if let (MountType::Bind, Some(source)) = (mount.mount_type, &mount.source) {
+            risks.push(format!("mount '{source}' from the host"));
+        }
+    }
+    for (name, volume) in &config.volumes {
+        let source = match &volume.seed {
+            Some(Seed::Directory { directory }) => directory,
+            Some(Seed::Tarball { tarball }) => tarball,
+            _ => continue,
+        };
+        // Seeds are found the same way as when seeding the volume.
+        let inside = expand_path(&source.to_string_lossy(), |var| std::env::var(var).ok())
+            .is_ok_and(|expanded| is_within(directory, &floki_root.join(expanded)));
+        if !inside {
+            risks.push(format!(
+                "copy '{}' from the host into the volume '{name}'",
+                source.display()
+            ));
+        }
+    }
+    risks
+}
+
+/// Whether a path is a directory or inside it, once symlinks are resolved.
+/// Paths which can't be resolved are assumed to be outside.
+fn is_within(directory: &Path, path: &Path) -> bool {
+    match (directory.canonicalize(), path.canonicalize()) {
+        (Ok(directory), Ok(path)) => path.starts_with(directory),
+        _ => false,
+    }
+}
+
+/// Check the user trusts a configuration file to do anything risky,
+/// asking them if possible
+pub(crate) fn ensure_trusted(
+    workspace: &Path,
+    config_file: &Path,
+    config: &FlokiConfig,
+) -> Result<(), Error> {
+    let interactive = atty::is(atty::Stream::Stdin) && atty::is(atty::Stream::Stderr);
+    check_trust(
+        &TrustStore::new(workspace),
+        config_file,
+        config,
+        |status, risks| {
+            if interactive {
+                prompt(config_file, status, risks)
+            } else {
+                Ok(false)
+            }
+        },
+    )
+}
+
+/// Check the user trusts a configuration file, asking them with the given
+/// function and recording their answer if they do
+fn check_trust<F>(
+    store: &TrustStore,
+    config_file: &Path,
+    config: &FlokiConfig,
+    ask: F,
+) -> Result<(), Error>
+where
+    F: FnOnce(TrustStatus, &[String]) -> Result<bool, Error>,
+{
+    let risks = risks(config_file, config);
+    if risks.is_empty() {
+        return Ok(());
+    }
+    let status = store.status(config_file, config)?;
+    debug!("Configuration file {config_file:?} is {status:?}");
+    if status == TrustStatus::Trusted {
+        return Ok(());
+    }
+    if ask(status, &risks)? {
+        return store.trust(config_file, config);
+    }
+    Err(FlokiError::UntrustedConfig {
+        name: config_file.display().to_string(),
+        changed: status == TrustStatus::Changed,
+        risks,
+    }
+    .into())
+}
+
+fn prompt(config_file: &Path, status: TrustStatus, risks: &[String]) -> Result<bool, Error> {
+    let mut stderr = io::stderr();
+    match status {
+        TrustStatus::Changed => writeln!(
+            stderr,
+            "{} has changed since it was trusted, and would:",
+            config_file.display()
+        )?,
+        _ => writeln!(
+            stderr,
+            "{} hasn't been trusted, and would:",
+            config_file.display()
+        )?,
+    }
+    for risk in risks {
+        writeln!(stderr, "  - {risk}")?;
+    }
+    write!(stderr, "Trust this configuration? [y/N] ")?;
+    stderr.flush()?;
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::ConfigFormat;
+
+    fn parse(yaml: &str) -> FlokiConfig {
+        ConfigFormat::Yaml.parse(yaml, "floki.yaml").unwrap()
+    }
+
+    #[test]
+    fn test_risks() {
+        let config_file = Path::new("/project/floki.yaml");
+        assert!(risks(config_file, &parse("image: debian")).is_empty());
+        assert_eq!(
+            risks(
+                config_file,
+                &parse(
+                    r#"
+image:
+  exec:
+    command: ./build.sh
+    args: ["--tag", "dev env"]
+    image: dev
+dind: true
+docker_switches:
+  - --privileged
+mounts:
+  - source: /
+    target: /host
+  - type: tmpfs
+    target: /tmp
+volumes:
+  keys:
+    mount: /keys
+    seed:
+      directory: ~/.ssh
+  toolchain:
+    mount: /opt
+    seed:
+      image: true
+"#
+                )
+            ),
+            vec![
+                "run `./build.sh --tag 'dev env'` on the host to obtain the image",
+                "run a privileged docker-in-docker container",
+                "pass `--privileged` to docker",
+                "mount '/' from the host",
+                "copy '~/.ssh' from the host into the volume 'keys'",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_root_outside_the_project_is_a_risk() -> Result<(), Error> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let project = tmp_dir.path().join("project");
+        std::fs::create_dir_all(project.join("src"))?;
+        let config_file = project.join("floki.yaml");
+
+        assert!(risks(&config_file, &parse("image: debian\nroot: .")).is_empty());
+        assert!(risks(&config_file, &parse("image: debian\nroot: src")).is_empty());
+        assert_eq!(
+            risks(&config_file, &parse("image: debian\nroot: ..")),
+            vec!["mount '..' from the host as the floki root"]
+        );
+        assert_eq!(
+            risks(&config_file, &parse("image: debian\nroot: /")),
+            vec!["mount '/' from the host as the floki root"]
+        );
+
+        let seeded = |seed: &str| {
+            parse(&format!(
+                "image: debian\nvolumes:\n  cache:\n    mount: /c\n    seed: {seed}"
+            ))
+        };
+        assert!(risks(&config_file, &seeded("{directory: src}")).is_empty());
+        assert_eq!(
+            risks(&config_file, &seeded("{tarball: ../cache.tar}")),
+            vec!["copy '../cache.tar' from the host into the volume 'cache'"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_trust() -> Result<(), Error> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let store = TrustStore::new(tmp_dir.path());
+        let config_file = Path::new("/project/floki.yaml");
+        let config = parse("image: debian\ndind: true");
+        let changed = parse("image: debian\ndind: true\ndocker_switches: [--privileged]");
+
+        // Safe configurations never need trusting.
+        check_trust(&store, config_file, &parse("image: debian"), |_, _| {
+            panic!("shouldn't ask")
+        })?;
+
+        let error = check_trust(&store, config_file, &config, |status, _| {
+            assert_eq!(status, TrustStatus::Unknown);
+            Ok(false)
+        })
+        .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<FlokiError>(),
+            Some(FlokiError::UntrustedConfig { changed: false, .. })
+        ));
+
+        check_trust(&store, config_file, &config, |_, _| Ok(true))?;
+        check_trust(&store, config_file, &config, |_, _| panic!("shouldn't ask"))?;
+        assert_eq!(store.status(config_file, &changed)?, TrustStatus::Changed);
+
+        assert!(store.untrust(config_file)?);
+        assert!(!store.untrust(config_file)?);
+        assert_eq!(store.status(config_file, &config)?, TrustStatus::Unknown);
+        Ok(())
+    }
+}
@@ -0,0 +1,166 @@
+/// Checks that a floki configuration can be used, beyond whether it parses
+use crate::config::{FlokiConfig, Seed, Shell};
+use crate::environment::Environment;
+use crate::mounts::{expand_path, resolve_mounts};
+use crate::spec::{decompose_switches, get_working_directory};
+
+use std::env;
+use std::path::Path;
+
+/// Check a parsed configuration, returning every problem found rather
+/// than stopping at the first
+pub(crate) fn validate(config: &FlokiConfig, environ: &mut Environment) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    if let Err(e) = environ.apply_config_root(config.root.as_deref()) {
+        problems.push(format!("root: {e}"));
+    }
+    let floki_root = environ.floki_root.clone();
+
+    let shells = match &config.shell {
+        Shell::Shell(shell) => vec![("shell", shell)],
+        Shell::TwoShell { inner, outer } => vec![("shell.inner", inner), ("shell.outer", outer)],
+    };
+    for (key, shell) in shells {
+        match shell_words::split(shell) {
+            Ok(words) if words.is_empty() => problems.push(format!("{key}: the shell is empty")),
+            Ok(_) => (),
+            Err(e) => problems.push(format!("{key}: '{shell}' can't be split: {e}")),
+        }
+    }
+
+    let missing = config
+        .image
+        .input_paths(&floki_root)
+        .into_iter()
+        .filter(|p| !p.exists())
+        .collect::<Vec<_>>();
+    for path in &missing {
+        problems.push(format!("image: '{}' does not exist", path.display()));
+    }
+    if missing.is_empty() {
+        if let Err(e) = config.image.name() {
+            problems.push(format!("image: {e}"));
+        }
+    }
+
+    if let Err(e) = get_working_directory(
+        &environ.current_directory,
+        &floki_root,
+        &config.mount,
+        config.workdir.as_deref(),
+    ) {
+        problems.push(format!("workdir: {e}"));
+    }
+
+    for (i, switch) in config.docker_switches.iter().enumerate() {
+        if let Err(e) = decompose_switches(std::slice::from_ref(switch)) {
+            problems.push(format!("docker_switches[{i}]: {e}"));
+        }
+    }
+
+    for (i, mount) in config.mounts.iter().enumerate() {
+        if let Err(e) = resolve_mounts(std::slice::from_ref(mount), &floki_root) {
+            problems.push(format!("mounts[{i}]: {e}"));
+        }
+    }
+
+    for (name, volume) in &config.volumes {
+        if !volume.mount.is_absolute() {
+            problems.push(format!(
+                "volumes.{name}.mount: '{}' must be an absolute path",
+                volume.mount.display()
+            ));
+        }
+        let seed_path = match &volume.seed {
+            Some(Seed::Directory { directory }) => Some(directory),
+            Some(Seed::Tarball { tarball }) => Some(tarball),
+            _ => None,
+        };
+        if let Some(seed_path) = seed_path {
+            if let Err(e) = check_host_path(seed_path, &floki_root) {
+                problems.push(format!("volumes.{name}.seed: {e}"));
+            }
+        }
+    }
+
+    problems
+}
+
+/// Check that a host path, relative to the floki root, exists
+fn check_host_path(path: &Path, floki_root: &Path) -> Result<(), String> {
+    let expanded = expand_path(&path.to_string_lossy(), |var| env::var(var).ok())?;
+    let full_path = floki_root.join(expanded);
+    if full_path.exists() {
+        Ok(())
+    } else {
+        Err(format!("'{}' does not exist", full_path.display()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::ConfigFormat;
+    use crate::environment::{RootSource, Terminal, User};
+
+    #[test]
+    fn test_validate_reports_every_problem() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let root = tmp_dir.path().canonicalize()?;
+        let yaml = r#"
+image:
+  build:
+    name: foo
+    dockerfile: missing/Dockerfile
+shell: "bash 'unterminated"
+docker_switches:
+  - -e OK=1
+  - -e "BAD
+volumes:
+  cache:
+    mount: relative
+    seed:
+      directory: missing
+"#;
+        let config: FlokiConfig = ConfigFormat::Yaml.parse(yaml, "floki.yaml")?;
+        let mut environ = Environment {
+            user_details: User::current(),
+            current_directory: root.clone(),
+            floki_root: root.clone(),
+            root_source: RootSource::ConfigLocation,
+            config_file: root.join("floki.yaml"),
+            config_format: ConfigFormat::Yaml,
+            override_files: Vec::new(),
+            user_config_file: None,
+            policy_files: Vec::new(),
+            ssh_agent_socket: None,
+            floki_workspace: root.join(".floki"),
+            terminal: Terminal {
+                tty: false,
+                interactive: false,
+            },
+            template_vars: serde_json::Map::new(),
+            template_path: Vec::new(),
+            allowed_template_paths: Vec::new(),
+        };
+
+        let problems = validate(&config, &mut environ);
+
+        let fields = problems
+            .iter()
+            .map(|p| p.split(':').next().unwrap_or_default())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fields,
+            vec![
+                "shell",
+                "image",
+                "docker_switches[1]",
+                "volumes.cache.mount",
+                "volumes.cache.seed"
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,141 @@
+/// Variables given on the command line for templates, available as `vars`
+use crate::config::ConfigFormat;
+use crate::errors::FlokiError;
+use crate::merge::as_object;
+
+use serde_json::{Map, Value};
+use std::path::PathBuf;
+
+/// A variable set with `--set key=value`. Dotted keys set values inside
+/// nested maps, e.g. `--set image.tag=latest`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct TemplateVar {
+    key: Vec<String>,
+    value: String,
+}
+
+impl std::str::FromStr for TemplateVar {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid variable '{s}' - expected KEY=VALUE");
+        let (key, value) = s.split_once('=').ok_or_else(invalid)?;
+        let key = key.split('.').map(String::from).collect::<Vec<_>>();
+        if key.iter().any(String::is_empty) {
+            return Err(invalid());
+        }
+        Ok(TemplateVar {
+            key,
+            value: value.to_string(),
+        })
+    }
+}
+
+/// Build the template variables from values files, applied in order, and
+/// then the variables set individually, which take precedence
+pub(crate) fn template_vars(
+    values_files: &[PathBuf],
+    sets: &[TemplateVar],
+) -> Result<Map<String, Value>, FlokiError> {
+    let mut vars = Map::new();
+    for file in values_files {
+        let name = file.display().to_string();
+        let content =
+            std::fs::read_to_string(file).map_err(|e| FlokiError::ProblemOpeningValuesFile {
+                name: name.clone(),
+                error: e,
+            })?;
+        let values: Value = ConfigFormat::from_path(file).parse(&content, &name)?;
+        match as_object(values) {
+            Value::Object(values) => merge_vars(&mut vars, values),
+            _ => return Err(FlokiError::ValuesFileNotAMap { name }),
+        }
+    }
+    for set in sets {
+        set_var(&mut vars, &set.key, Value::String(set.value.clone()));
+    }
+    Ok(vars)
+}
+
+/// Merge maps key by key, replacing any other values
+fn merge_vars(vars: &mut Map<String, Value>, overlay: Map<String, Value>) {
+    for (name, value) in overlay {
+        match (vars.get_mut(&name), value) {
+            (Some(Value::Object(existing)), Value::Object(value)) => merge_vars(existing, value),
+            (_, value) => {
+                vars.insert(name, value);
+            }
+        }
+    }
+}
+
+/// Set a value at a dotted key, replacing anything in the way with a map
+fn set_var(vars: &mut Map<String, Value>, key: &[String], value: Value) {
+    match key {
+        [] => (),
+        [name] => {
+            vars.insert(name.clone(), value);
+        }
+        [name, rest @ ..] => {
+            let child = vars
+                .entry(name.clone())
+                .or_insert_with(|| Value::Object(Map::new()));
+            if !child.is_object() {
+                *child = Value::Object(Map::new());
+            }
+            if let Value::Object(child) = child {
+                set_var(child, rest, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_template_var() {
+        assert_eq!(
+            "image.tag=v1=2".parse(),
+            Ok(TemplateVar {
+                key: vec!["image".into(), "tag".into()],
+                value: "v1=2".into(),
+            })
+        );
+        assert!("image".parse::<TemplateVar>().is_err());
+        assert!("image..tag=v1".parse::<TemplateVar>().is_err());
+        assert!("=v1".parse::<TemplateVar>().is_err());
+    }
+
+    #[test]
+    fn test_template_vars() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let base = tmp_dir.path().join("base.yaml");
+        let ci = tmp_dir.path().join("ci.json");
+        std::fs::write(&base, "image:\n  name: build\n  tag: latest\ndebug: true\n")?;
+        std::fs::write(&ci, r#"{"image": {"tag": "stable"}, "debug": false}"#)?;
+
+        let vars = template_vars(
+            &[base, ci],
+            &["image.tag=v2".parse()?, "debug.level=3".parse()?],
+        )?;
+        assert_eq!(
+            Value::Object(vars),
+            json!({"image": {"name": "build", "tag": "v2"}, "debug": {"level": "3"}})
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_values_file_must_be_a_map() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let values = tmp_dir.path().join("values.yaml");
+        std::fs::write(&values, "- a\n- b\n")?;
+
+        let error = template_vars(&[values], &[]).unwrap_err();
+        assert!(matches!(error, FlokiError::ValuesFileNotAMap { .. }));
+        Ok(())
+    }
+}
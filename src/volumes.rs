@@ -1,28 +1,115 @@
 use std::path;
+use std::process::{Command, Stdio};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{collections::BTreeMap, os::unix::prelude::OsStrExt};
 
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
-use crate::config::Volume;
+use crate::command::DockerCommandBuilder;
+use crate::config::{MountType, Volume, VolumeBackend};
+use crate::errors::{FlokiError, FlokiSubprocessExitStatus};
+use crate::mounts::ResolvedMount;
 
 static VOLUME_DIRECTORY: &str = "volumes/";
 
+/// Directory holding a metadata file for each volume floki has used
+static METADATA_DIRECTORY: &str = "volume-metadata/";
+
+/// Label attached to docker volumes created by floki
+static VOLUME_LABEL: &str = "floki.volume";
+
+/// Where the content of a floki volume lives
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum VolumeSource {
+    /// A directory on the host
+    Directory(path::PathBuf),
+    /// A named docker volume
+    Docker(String),
+}
+
+/// A floki volume together with the location of its content
+#[derive(Debug)]
+pub(crate) struct ResolvedVolume<'a> {
+    /// The name of the volume in the floki configuration
+    pub(crate) name: &'a str,
+    /// The volume configuration
+    pub(crate) config: &'a Volume,
+    /// Where the content of the volume is stored
+    pub(crate) source: VolumeSource,
+}
+
 pub(crate) fn resolve_volume_mounts<'a>(
     config_filepath: &path::Path,
     work_path: &path::Path,
     volumes: &'a BTreeMap<String, Volume>,
-) -> Vec<(path::PathBuf, &'a path::PathBuf)> {
+) -> Vec<ResolvedVolume<'a>> {
     volumes
         .iter()
-        .map(|(name, volume)| {
-            (
-                cache_path(work_path, config_filepath, name, volume),
-                &volume.mount,
-            )
+        .map(|(name, volume)| ResolvedVolume {
+            name,
+            config: volume,
+            source: match volume.backend {
+                VolumeBackend::Host => {
+                    VolumeSource::Directory(cache_path(work_path, config_filepath, name, volume))
+                }
+                VolumeBackend::Docker => {
+                    VolumeSource::Docker(docker_volume_name(config_filepath, name, volume))
+                }
+            },
         })
         .collect()
 }
 
+/// Metadata recorded alongside each volume floki uses
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct VolumeMetadata {
+    /// The name of the volume in the floki configuration
+    name: String,
+    /// Whether the volume is shared between configuration files
+    shared: bool,
+    /// The configuration file which last used the volume
+    config: path::PathBuf,
+    /// Where the content of the volume is stored
+    source: VolumeSource,
+    /// When the volume was last used, in seconds since the epoch
+    last_used: u64,
+}
+
+/// A volume found in the floki workspace
+#[derive(Debug)]
+struct KnownVolume {
+    /// Identifier of the volume's metadata file
+    id: String,
+    /// Recorded metadata, if any. Volumes created by older versions
+    /// of floki have no metadata.
+    metadata: Option<VolumeMetadata>,
+    /// Where the content of the volume is stored
+    source: VolumeSource,
+    /// When the volume was last used
+    last_used: SystemTime,
+}
+
+/// Find a single volume from the configuration by name
+pub(crate) fn resolve_volume<'a>(
+    config_filepath: &path::Path,
+    work_path: &path::Path,
+    volumes: &'a BTreeMap<String, Volume>,
+    name: &str,
+) -> Result<ResolvedVolume<'a>, Error> {
+    resolve_volume_mounts(config_filepath, work_path, volumes)
+        .into_iter()
+        .find(|v| v.name == name)
+        .ok_or_else(|| {
+            FlokiError::UnknownVolume {
+                name: name.to_string(),
+            }
+            .into()
+        })
+}
+
 fn cache_path(
     work_path: &path::Path,
     config_filepath: &path::Path,
@@ -33,6 +120,10 @@ fn cache_path(
     work_path.join(VOLUME_DIRECTORY).join::<String>(folder)
 }
 
+fn docker_volume_name(config_filepath: &path::Path, name: &str, config: &Volume) -> String {
+    "floki-".to_string() + &prefix_cache(config.shared, config_filepath) + name
+}
+
 fn prefix_cache(shared: bool, config_filepath: &path::Path) -> String {
     if shared {
         "".into()
@@ -41,7 +132,7 @@ fn prefix_cache(shared: bool, config_filepath: &path::Path) -> String {
     }
 }
 
-fn hash_path(path: &path::Path) -> String {
+pub(crate) fn hash_path(path: &path::Path) -> String {
     let mut hasher = Sha256::new();
     hasher.update(path.as_os_str().as_bytes());
     hasher
@@ -51,6 +142,420 @@ fn hash_path(path: &path::Path) -> String {
         .collect()
 }
 
+/// Create the docker volume backing a floki volume if it doesn't exist
+/// yet, returning whether it was created
+pub(crate) fn ensure_docker_volume(
+    docker_name: &str,
+    volume_name: &str,
+    config_filepath: &path::Path,
+) -> Result<bool, Error> {
+    let exists = Command::new("docker")
+        .args(["volume", "inspect", docker_name])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map_err(|e| FlokiError::FailedToLaunchDocker { error: e })?
+        .success();
+
+    if exists {
+        return Ok(false);
+    }
+
+    debug!("Creating docker volume {docker_name}");
+    docker_volume_command(
+        &[
+            "create".into(),
+            "--label".into(),
+            format!("{VOLUME_LABEL}={volume_name}"),
+            "--label".into(),
+            format!("floki.config={}", config_filepath.display()),
+            docker_name.into(),
+        ],
+        true,
+    )?;
+    Ok(true)
+}
+
+impl ResolvedVolume<'_> {
+    /// The identifier used for the volume's metadata file
+    fn id(&self) -> String {
+        match &self.source {
+            VolumeSource::Directory(path) => path
+                .file_name()
+                .map(|f| f.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            VolumeSource::Docker(name) => name.clone(),
+        }
+    }
+}
+
+fn metadata_path(work_path: &path::Path, id: &str) -> path::PathBuf {
+    work_path
+        .join(METADATA_DIRECTORY)
+        .join(id.to_string() + ".json")
+}
+
+/// Record that a volume has been used by a configuration file
+pub(crate) fn record_volume_use(
+    work_path: &path::Path,
+    config_filepath: &path::Path,
+    volume: &ResolvedVolume,
+) -> Result<(), Error> {
+    let metadata = VolumeMetadata {
+        name: volume.name.to_string(),
+        shared: volume.config.shared,
+        config: config_filepath.to_path_buf(),
+        source: volume.source.clone(),
+        last_used: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+    };
+    let path = metadata_path(work_path, &volume.id());
+    std::fs::create_dir_all(work_path.join(METADATA_DIRECTORY))?;
+    std::fs::write(path, serde_json::to_string_pretty(&metadata)?)?;
+    Ok(())
+}
+
+/// Find all volumes recorded in the floki workspace, along with any
+/// volume directories created before floki recorded metadata.
+fn known_volumes(work_path: &path::Path) -> Result<Vec<KnownVolume>, Error> {
+    let mut volumes: BTreeMap<String, KnownVolume> = BTreeMap::new();
+
+    let metadata_dir = work_path.join(METADATA_DIRECTORY);
+    if metadata_dir.is_dir() {
+        for entry in std::fs::read_dir(&metadata_dir)? {
+            let path = entry?.path();
+            let id = match path.file_stem() {
+                Some(stem) if path.extension() == Some("json".as_ref()) => {
+                    stem.to_string_lossy().to_string()
+                }
+                _ => continue,
+            };
+            let metadata: VolumeMetadata = match std::fs::read_to_string(&path)
+                .map_err(Error::from)
+                .and_then(|c| Ok(serde_json::from_str(&c)?))
+            {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    warn!(
+                        "Ignoring unreadable volume metadata {}: {e}",
+                        path.display()
+                    );
+                    continue;
+                }
+            };
+            let source = metadata.source.clone();
+            let last_used = UNIX_EPOCH + Duration::from_secs(metadata.last_used);
+            volumes.insert(
+                id.clone(),
+                KnownVolume {
+                    id,
+                    metadata: Some(metadata),
+                    source,
+                    last_used,
+                },
+            );
+        }
+    }
+
+    let volume_dir = work_path.join(VOLUME_DIRECTORY);
+    if volume_dir.is_dir() {
+        for entry in std::fs::read_dir(&volume_dir)? {
+            let entry = entry?;
+            let id = entry.file_name().to_string_lossy().to_string();
+            if volumes.contains_key(&id) {
+                continue;
+            }
+            let last_used = entry.metadata()?.modified()?;
+            volumes.insert(
+                id.clone(),
+                KnownVolume {
+                    id,
+                    metadata: None,
+                    source: VolumeSource::Directory(entry.path()),
+                    last_used,
+                },
+            );
+        }
+    }
+
+    Ok(volumes.into_values().collect())
+}
+
+/// List all volumes floki has used, with their size and when they were last used
+pub(crate) fn list_volumes(work_path: &path::Path) -> Result<(), Error> {
+    let now = SystemTime::now();
+    let mut rows = vec![[
+        "NAME".to_string(),
+        "SCOPE".to_string(),
+        "SIZE".to_string(),
+        "LAST USED".to_string(),
+        "CONFIG".to_string(),
+    ]];
+    for volume in known_volumes(work_path)? {
+        let (name, scope, config) = match &volume.metadata {
+            Some(m) => (
+                m.name.clone(),
+                if m.shared { "shared" } else { "local" }.to_string(),
+                m.config.display().to_string(),
+            ),
+            None => (volume.id.clone(), "unknown".into(), "unknown".into()),
+        };
+        let size = describe_size(&volume.source);
+        let age = now.duration_since(volume.last_used).unwrap_or_default();
+        rows.push([name, scope, size, format_age(age), config]);
+    }
+
+    let mut widths = [0; 4];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    for row in &rows {
+        println!(
+            "{:<w0$}  {:<w1$}  {:>w2$}  {:<w3$}  {}",
+            row[0],
+            row[1],
+            row[2],
+            row[3],
+            row[4],
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2],
+            w3 = widths[3],
+        );
+    }
+    Ok(())
+}
+
+/// Show details of a floki volume
+pub(crate) fn inspect_volume(volume: &ResolvedVolume) -> Result<(), Error> {
+    match &volume.source {
+        VolumeSource::Directory(path) => {
+            println!("{}", path.display());
+            Ok(())
+        }
+        VolumeSource::Docker(name) => {
+            docker_volume_command(&["inspect".into(), name.clone()], false)
+        }
+    }
+}
+
+/// Remove a floki volume and its content
+pub(crate) fn clear_volume(work_path: &path::Path, volume: &ResolvedVolume) -> Result<(), Error> {
+    remove_source(&volume.source)?;
+    remove_metadata(work_path, &volume.id())
+}
+
+/// Remove all volumes which haven't been used for the given time
+pub(crate) fn prune_volumes(work_path: &path::Path, older_than: Duration) -> Result<(), Error> {
+    // Nothing can have been last used before the start of time
+    let cutoff = match SystemTime::now().checked_sub(older_than) {
+        Some(cutoff) => cutoff,
+        None => return Ok(()),
+    };
+    for volume in known_volumes(work_path)? {
+        if volume.last_used >= cutoff {
+            continue;
+        }
+        match remove_source(&volume.source) {
+            Ok(()) => {
+                remove_metadata(work_path, &volume.id)?;
+                println!("Removed volume {}", volume.id);
+            }
+            Err(e) => warn!("Unable to remove volume {}: {e}", volume.id),
+        }
+    }
+    Ok(())
+}
+
+/// Remove all docker volumes created by floki which aren't used by any
+/// container, along with their metadata
+pub(crate) fn prune_docker_volumes(work_path: &path::Path) -> Result<(), Error> {
+    let output = Command::new("docker")
+        .args(["volume", "ls", "--quiet", "--filter"])
+        .arg(format!("label={VOLUME_LABEL}"))
+        .args(["--filter", "dangling=true"])
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| FlokiError::FailedToLaunchDocker { error: e })?;
+
+    if !output.status.success() {
+        return Err(volume_command_failed(output.status).into());
+    }
+
+    let known = known_volumes(work_path)?;
+    for name in String::from_utf8_lossy(&output.stdout).lines() {
+        docker_volume_command(&["rm".into(), name.to_string()], false)?;
+        for volume in &known {
+            if matches!(&volume.source, VolumeSource::Docker(docker) if docker == name) {
+                remove_metadata(work_path, &volume.id)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Delete the content backing a volume
+pub(crate) fn remove_source(source: &VolumeSource) -> Result<(), Error> {
+    match source {
+        VolumeSource::Directory(path) => {
+            if path.exists() {
+                info!("Removing volume directory {}", path.display());
+                std::fs::remove_dir_all(path)?;
+            }
+            Ok(())
+        }
+        VolumeSource::Docker(name) => docker_volume_command(&["rm".into(), name.clone()], true),
+    }
+}
+
+fn remove_metadata(work_path: &path::Path, id: &str) -> Result<(), Error> {
+    let path = metadata_path(work_path, id);
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Describe the size of a volume, without failing for volumes whose
+/// directories have been removed or can't be read
+fn describe_size(source: &VolumeSource) -> String {
+    match source {
+        VolumeSource::Directory(path) if !path.exists() => "missing".into(),
+        VolumeSource::Directory(path) => match directory_size(path) {
+            Ok(size) => format_size(size),
+            Err(e) => {
+                debug!("Unable to find the size of {}: {e}", path.display());
+                "unknown".into()
+            }
+        },
+        VolumeSource::Docker(_) => "docker".into(),
+    }
+}
+
+/// Total size of the files in a directory, without following symlinks
+fn directory_size(path: &path::Path) -> Result<u64, Error> {
+    let metadata = std::fs::symlink_metadata(path)?;
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        size += directory_size(&entry?.path())?;
+    }
+    Ok(size)
+}
+
+fn format_size(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", units[unit])
+    }
+}
+
+fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    match secs {
+        0..=59 => "just now".into(),
+        60..=3599 => format!("{} minutes ago", secs / 60),
+        3600..=86399 => format!("{} hours ago", secs / 3600),
+        _ => format!("{} days ago", secs / 86400),
+    }
+}
+
+/// Mount a volume into a docker command at the given path
+pub(crate) fn mount_volume(
+    cmd: DockerCommandBuilder,
+    volume: &ResolvedVolume,
+    target: &path::Path,
+) -> DockerCommandBuilder {
+    match &volume.source {
+        VolumeSource::Directory(src) => cmd.add_volume((src, &target.to_path_buf())),
+        VolumeSource::Docker(name) => cmd.add_mount(
+            ResolvedMount {
+                mount_type: MountType::Volume,
+                source: Some(name.clone()),
+                target: target.to_path_buf(),
+                readonly: false,
+            }
+            .mount_argument(),
+        ),
+    }
+}
+
+/// Change the ownership of a volume's content. This runs `chown` as root
+/// in a container, since the content may be owned by other users. Volume
+/// directories which don't exist yet are skipped, rather than letting
+/// docker create them owned by root.
+pub(crate) fn chown_volume(
+    volume: &ResolvedVolume,
+    image: &str,
+    uid: u32,
+    gid: u32,
+) -> Result<(), Error> {
+    if let VolumeSource::Directory(path) = &volume.source {
+        if !path.exists() {
+            warn!(
+                "Not changing owner of volume '{}', which hasn't been created yet",
+                volume.name
+            );
+            return Ok(());
+        }
+    }
+    info!("Changing owner of volume '{}' to {uid}:{gid}", volume.name);
+    let target = path::Path::new("/floki-volume");
+    mount_volume(DockerCommandBuilder::new(image), volume, target)
+        .add_docker_switch("--user=0:0")
+        .add_docker_switch("--entrypoint=chown")
+        .run([
+            "-R".to_string(),
+            format!("{uid}:{gid}"),
+            target.display().to_string(),
+        ])
+}
+
+/// Run `docker volume` with the given arguments
+fn docker_volume_command(args: &[String], quiet: bool) -> Result<(), Error> {
+    let stdout = if quiet {
+        Stdio::null()
+    } else {
+        Stdio::inherit()
+    };
+    let exit_status = Command::new("docker")
+        .arg("volume")
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(stdout)
+        .status()
+        .map_err(|e| FlokiError::FailedToLaunchDocker { error: e })?;
+
+    if exit_status.success() {
+        Ok(())
+    } else {
+        Err(volume_command_failed(exit_status).into())
+    }
+}
+
+fn volume_command_failed(exit_status: std::process::ExitStatus) -> FlokiError {
+    FlokiError::FailedToManageVolume {
+        exit_status: FlokiSubprocessExitStatus {
+            process_description: "docker volume".into(),
+            exit_status,
+        },
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -65,6 +570,7 @@ mod test {
             &Volume {
                 shared: true,
                 mount: "/".into(),
+                ..Default::default()
             },
         );
         let cache_2 = cache_path(
@@ -74,6 +580,7 @@ mod test {
             &Volume {
                 shared: true,
                 mount: "/".into(),
+                ..Default::default()
             },
         );
 
@@ -89,6 +596,7 @@ mod test {
             &Volume {
                 shared: false,
                 mount: "/".into(),
+                ..Default::default()
             },
         );
         let cache_2 = cache_path(
@@ -98,6 +606,7 @@ mod test {
             &Volume {
                 shared: false,
                 mount: "/".into(),
+                ..Default::default()
             },
         );
 
@@ -113,6 +622,7 @@ mod test {
             &Volume {
                 shared: true,
                 mount: "/".into(),
+                ..Default::default()
             },
         );
         let cache_local = cache_path(
@@ -122,6 +632,7 @@ mod test {
             &Volume {
                 shared: false,
                 mount: "/".into(),
+                ..Default::default()
             },
         );
 
@@ -137,6 +648,7 @@ mod test {
             &Volume {
                 shared: false,
                 mount: "/".into(),
+                ..Default::default()
             },
         );
         let cache_local = cache_path(
@@ -146,12 +658,100 @@ mod test {
             &Volume {
                 shared: false,
                 mount: "/".into(),
+                ..Default::default()
             },
         );
 
         assert_ne!(cache_shared, cache_local);
     }
 
+    #[test]
+    fn test_docker_volume_name() {
+        let config = Path::new("/floki/root/1/floki.yaml");
+        let shared = Volume {
+            shared: true,
+            backend: VolumeBackend::Docker,
+            ..Default::default()
+        };
+        let local = Volume {
+            shared: false,
+            backend: VolumeBackend::Docker,
+            ..Default::default()
+        };
+
+        assert_eq!(docker_volume_name(config, "cache", &shared), "floki-cache");
+        assert_eq!(
+            docker_volume_name(config, "cache", &local),
+            format!("floki-{}-cache", hash_path(config))
+        );
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(12), "12 B");
+        assert_eq!(format_size(2048), "2.0 KiB");
+        assert_eq!(format_size(5 * 1024 * 1024 * 1024), "5.0 GiB");
+    }
+
+    #[test]
+    fn test_chown_skips_uncreated_volumes() -> Result<(), Error> {
+        let work_path = tempfile::TempDir::new()?;
+        let mut volumes = BTreeMap::new();
+        volumes.insert("cache".to_string(), Volume::default());
+        let config = Path::new("/floki/root/1/floki.yaml");
+        for volume in resolve_volume_mounts(config, work_path.path(), &volumes) {
+            chown_volume(&volume, "no-such-image", 1000, 1000)?;
+            if let VolumeSource::Directory(path) = &volume.source {
+                assert!(!path.exists());
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_describe_size() -> Result<(), Error> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        std::fs::write(tmp_dir.path().join("file"), [0; 10])?;
+        assert_eq!(
+            describe_size(&VolumeSource::Directory(tmp_dir.path().into())),
+            "10 B"
+        );
+        assert_eq!(
+            describe_size(&VolumeSource::Directory(tmp_dir.path().join("removed"))),
+            "missing"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_known_volumes_includes_recorded_and_legacy() -> Result<(), Error> {
+        let work_path = tempfile::TempDir::new()?;
+        let config = Path::new("/floki/root/1/floki.yaml");
+        let mut volumes = BTreeMap::new();
+        volumes.insert("cache".to_string(), Volume::default());
+        for volume in resolve_volume_mounts(config, work_path.path(), &volumes) {
+            record_volume_use(work_path.path(), config, &volume)?;
+        }
+        std::fs::create_dir_all(work_path.path().join(VOLUME_DIRECTORY).join("legacy"))?;
+
+        let known = known_volumes(work_path.path())?;
+        assert_eq!(known.len(), 2);
+        let recorded = known
+            .iter()
+            .find_map(|v| v.metadata.as_ref())
+            .expect("missing recorded volume");
+        assert_eq!(recorded.name, "cache");
+        assert_eq!(recorded.config, config);
+        assert!(known
+            .iter()
+            .any(|v| v.id == "legacy" && v.metadata.is_none()));
+
+        // Volumes can't be older than the start of time.
+        prune_volumes(work_path.path(), Duration::MAX)?;
+        assert_eq!(known_volumes(work_path.path())?.len(), 2);
+        Ok(())
+    }
+
     #[test]
     fn test_path_sha() {
         let path = Path::new("/floki/root/1/floki.yaml");